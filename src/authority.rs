@@ -0,0 +1,537 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use crate::{
+    name::DomainName,
+    rdata::{RData, RecordType},
+    zonefile::{self, ZoneFileError},
+    DNSQuery, DNSResource, RCODE,
+};
+
+/// Most CNAMEs followed between the zones we serve for one answer
+const MAX_CNAMES: usize = 8;
+
+/// The QTYPE asking for every record at a name
+const ANY: RecordType = RecordType::Unknown(255);
+
+/// One `--zone` to serve, written as `<origin>=<path>`, e.g.
+/// `example.com=zones/example.com.zone`
+#[derive(Debug, Clone)]
+pub struct ZoneSource {
+    pub origin: DomainName,
+    pub path: PathBuf,
+}
+
+impl FromStr for ZoneSource {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (origin, path) = s.split_once('=').ok_or(())?;
+        if path.is_empty() {
+            return Err(());
+        }
+
+        Ok(ZoneSource {
+            origin: origin.parse().map_err(|_| ())?,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+/// Reasons a zone can't be served
+#[derive(Debug, thiserror::Error)]
+pub enum ZoneError {
+    #[error(transparent)]
+    File(#[from] ZoneFileError),
+    #[error("zone {0} has no SOA record at its apex")]
+    NoSoa(DomainName),
+}
+
+/// What a zone has to say about a name
+#[derive(Debug)]
+pub enum Lookup {
+    /// The records asked for
+    Found(Vec<DNSResource>),
+    /// The name is an alias, the answer is wherever it points
+    Cname(DNSResource, DomainName),
+    /// The name is in a zone delegated away from us
+    Referral {
+        ns: Vec<DNSResource>,
+        glue: Vec<DNSResource>,
+    },
+    NoData,
+    NxDomain,
+}
+
+/// A zone we are authoritative for, loaded from a master file
+#[derive(Debug)]
+pub struct Zone {
+    origin: DomainName,
+    /// Records by owner. Canonical ordering puts a name's descendants right
+    /// after it, which is how empty non-terminals are found
+    names: BTreeMap<DomainName, Vec<DNSResource>>,
+}
+
+impl Zone {
+    pub fn load(source: &ZoneSource) -> Result<Zone, ZoneError> {
+        let records = zonefile::load(&source.path, &source.origin)?;
+        let mut names: BTreeMap<DomainName, Vec<DNSResource>> = BTreeMap::new();
+
+        for rr in records {
+            if !rr.name.is_subdomain_of(&source.origin) {
+                println!("Ignoring out of zone record {} in {}", rr, source.origin);
+                continue;
+            }
+
+            names.entry(rr.name.clone()).or_default().push(rr);
+        }
+
+        let zone = Zone {
+            origin: source.origin.clone(),
+            names,
+        };
+
+        if zone.soa().is_none() {
+            return Err(ZoneError::NoSoa(zone.origin));
+        }
+
+        Ok(zone)
+    }
+
+    pub fn origin(&self) -> &DomainName {
+        &self.origin
+    }
+
+    fn soa(&self) -> Option<&DNSResource> {
+        self.names
+            .get(&self.origin)?
+            .iter()
+            .find(|rr| rr.rtype() == RecordType::SOA)
+    }
+
+    /// The SOA to put in the authority section of a negative answer, with
+    /// its TTL cut down to the negative caching TTL, RFC 2308 section 3
+    fn negative_soa(&self) -> DNSResource {
+        let mut soa = self
+            .soa()
+            .expect("zones are only loaded with an SOA")
+            .clone();
+        if let RData::SOA { minimum, .. } = soa.rdata {
+            soa.ttl = soa.ttl.min(minimum);
+        }
+
+        soa
+    }
+
+    /// Records of the given type at `name`, whether or not it is below a
+    /// zone cut, as used for glue
+    fn records(&self, name: &DomainName, rtypes: &[RecordType]) -> Vec<DNSResource> {
+        self.names
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(|rr| rtypes.contains(&rr.rtype()))
+            .cloned()
+            .collect()
+    }
+
+    /// True if anything is owned by a name below `name`
+    fn has_descendants(&self, name: &DomainName) -> bool {
+        self.names
+            .range((Bound::Excluded(name), Bound::Unbounded))
+            .next()
+            .is_some_and(|(next, _)| next.is_subdomain_of(name))
+    }
+
+    /// The records the wildcard at the closest encloser of `qname` stands
+    /// in with, owned by `qname` itself, RFC 4592 section 3.3.1
+    ///
+    /// `qname` has to be a name that doesn't exist at all. Gives `None` when
+    /// there is no wildcard to use.
+    fn wildcard(&self, qname: &DomainName) -> Option<Vec<DNSResource>> {
+        // The closest encloser is the deepest ancestor that exists, even if
+        // only as an empty non-terminal. The apex always does
+        let encloser = (self.origin.label_count()..qname.label_count())
+            .rev()
+            .map(|count| qname.ancestor(count))
+            .find(|name| self.names.contains_key(name) || self.has_descendants(name))?;
+
+        let source = encloser.child(b"*").ok()?;
+        let Some(records) = self.names.get(&source) else {
+            // A wildcard with nothing but names below it still matches, it
+            // just has no data of any type
+            return self.has_descendants(&source).then(Vec::new);
+        };
+
+        let synthesised = records
+            .iter()
+            .map(|rr| DNSResource {
+                name: qname.clone(),
+                ..rr.clone()
+            })
+            .collect();
+
+        Some(synthesised)
+    }
+
+    /// Look `qname` up the way RFC 1034 section 4.3.2 does, for a name
+    /// already known to be in this zone
+    pub fn lookup(&self, qname: &DomainName, qtype: RecordType) -> Lookup {
+        // Walk down from just below the apex looking for a zone cut
+        for count in self.origin.label_count() + 1..=qname.label_count() {
+            let name = qname.ancestor(count);
+            let ns = self.records(&name, &[RecordType::NS]);
+
+            if !ns.is_empty() {
+                let glue = ns
+                    .iter()
+                    .filter_map(|rr| match &rr.rdata {
+                        RData::NS(target) => Some(target),
+                        _ => None,
+                    })
+                    .flat_map(|target| self.records(target, &[RecordType::A, RecordType::AAAA]))
+                    .collect();
+
+                return Lookup::Referral { ns, glue };
+            }
+        }
+
+        // A name that only exists as an empty non-terminal is never matched
+        // by a wildcard, RFC 4592 section 2.2.2
+        let records = match self.names.get(qname) {
+            Some(records) => records.clone(),
+            None if self.has_descendants(qname) => return Lookup::NoData,
+            None => match self.wildcard(qname) {
+                Some(records) => records,
+                None => return Lookup::NxDomain,
+            },
+        };
+
+        let cname = records.iter().find_map(|rr| match &rr.rdata {
+            RData::CNAME(target) => Some((rr.clone(), target.clone())),
+            _ => None,
+        });
+
+        let found = records
+            .into_iter()
+            .filter(|rr| qtype == ANY || rr.rtype() == qtype)
+            .collect::<Vec<_>>();
+        if !found.is_empty() {
+            return Lookup::Found(found);
+        }
+
+        match cname {
+            Some((rr, target)) => Lookup::Cname(rr, target),
+            None => Lookup::NoData,
+        }
+    }
+}
+
+/// An answer given with our own authority
+#[derive(Debug)]
+pub struct AuthAnswer {
+    pub rcode: RCODE,
+    /// False for a referral, where the answer belongs to someone else
+    pub authoritative: bool,
+    pub answer: Vec<DNSResource>,
+    pub authority: Vec<DNSResource>,
+    pub additional: Vec<DNSResource>,
+}
+
+/// Every zone we serve, by origin
+#[derive(Debug)]
+pub struct Authority {
+    zones: HashMap<DomainName, Zone>,
+}
+
+impl Authority {
+    pub fn load(sources: &[ZoneSource]) -> Result<Authority, ZoneError> {
+        let mut zones = HashMap::new();
+
+        for source in sources {
+            let zone = Zone::load(source)?;
+            println!(
+                "Serving zone {} with {} names",
+                zone.origin,
+                zone.names.len()
+            );
+
+            zones.insert(zone.origin.clone(), zone);
+        }
+
+        Ok(Authority { zones })
+    }
+
+    /// The most specific zone `name` falls in, if we serve any
+    fn zone_for(&self, name: &DomainName) -> Option<&Zone> {
+        let mut zone = name.clone();
+
+        loop {
+            if let Some(found) = self.zones.get(&zone) {
+                return Some(found);
+            }
+
+            zone = zone.parent()?;
+        }
+    }
+
+    /// Answer `query` from our zones, `None` if the name isn't in any of
+    /// them and the query should be refused
+    pub fn answer(&self, query: &DNSQuery) -> Option<AuthAnswer> {
+        let mut zone = self.zone_for(&query.qname)?;
+        let mut qname = query.qname.clone();
+
+        let mut answer = AuthAnswer {
+            rcode: RCODE::NoErr,
+            authoritative: true,
+            answer: vec![],
+            authority: vec![],
+            additional: vec![],
+        };
+
+        for _ in 0..MAX_CNAMES {
+            match zone.lookup(&qname, query.qtype) {
+                Lookup::Found(records) => answer.answer.extend(records),
+                Lookup::Cname(rr, target) => {
+                    answer.answer.push(rr);
+
+                    // Carry on through the alias as long as it stays in
+                    // zones we serve, the client can chase the rest
+                    if let Some(next) = self.zone_for(&target) {
+                        zone = next;
+                        qname = target;
+                        continue;
+                    }
+                }
+                Lookup::Referral { ns, glue } => {
+                    answer.authoritative = !answer.answer.is_empty();
+                    answer.authority = ns;
+                    answer.additional = glue;
+                }
+                Lookup::NoData => answer.authority.push(zone.negative_soa()),
+                Lookup::NxDomain => {
+                    answer.rcode = RCODE::NameErr;
+                    answer.authority.push(zone.negative_soa());
+                }
+            }
+
+            break;
+        }
+
+        // Addresses for the names the answer points at, when we have them
+        let targets = answer
+            .answer
+            .iter()
+            .filter_map(|rr| match &rr.rdata {
+                RData::NS(target)
+                | RData::MX {
+                    exchange: target, ..
+                } => Some(target),
+                RData::SRV { target, .. } => Some(target),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for target in targets {
+            if let Some(zone) = self.zone_for(target) {
+                answer
+                    .additional
+                    .extend(zone.records(target, &[RecordType::A, RecordType::AAAA]));
+            }
+        }
+
+        Some(answer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rdata::RecordClass, zonefile::TempZones};
+
+    const ZONE: &str = "
+$ORIGIN example.com.
+$TTL 3600
+@        SOA  ns1 hostmaster 1 2 3 4 300
+@        NS   ns1
+ns1      A    192.0.2.1
+www      A    192.0.2.80
+a.b.ent  A    192.0.2.9
+sub      NS   ns.sub
+ns.sub   A    192.0.2.53
+";
+
+    const WILDCARDS: &str = "
+$ORIGIN example.com.
+$TTL 3600
+@             SOA    ns1 hostmaster 1 2 3 4 300
+@             NS     ns1
+ns1           A      192.0.2.1
+www           A      192.0.2.80
+*             TXT    \"apex\"
+*.preview     A      192.0.2.200
+              TXT    \"preview\"
+host.preview  A      192.0.2.201
+a.b.ent       A      192.0.2.9
+*.alias       CNAME  www
+sub           NS     ns.sub
+ns.sub        A      192.0.2.53
+";
+
+    /// Serve `text` as the zone `example.com.`
+    fn authority(test: &str, text: &str) -> Authority {
+        let zone = TempZones::write(&format!("authority-{}", test), &[("example.zone", text)]);
+
+        Authority::load(&[ZoneSource {
+            origin: "example.com.".parse().unwrap(),
+            path: zone.path().to_path_buf(),
+        }])
+        .expect("load zone")
+    }
+
+    fn ask(authority: &Authority, name: &str, qtype: RecordType) -> Option<AuthAnswer> {
+        authority.answer(&DNSQuery {
+            qname: name.parse().unwrap(),
+            qtype,
+            qclass: RecordClass::IN,
+        })
+    }
+
+    #[test]
+    fn answers_with_authority() {
+        let authority = authority("answer", ZONE);
+        let answer = ask(&authority, "www.example.com.", RecordType::A).unwrap();
+
+        assert_eq!(answer.rcode, RCODE::NoErr);
+        assert!(answer.authoritative);
+        assert_eq!(answer.answer[0].rdata, RData::A([192, 0, 2, 80].into()));
+    }
+
+    #[test]
+    fn negative_answers_carry_the_soa() {
+        let authority = authority("negative", ZONE);
+
+        let nxdomain = ask(&authority, "nope.example.com.", RecordType::A).unwrap();
+        assert_eq!(nxdomain.rcode, RCODE::NameErr);
+        assert_eq!(nxdomain.authority[0].rtype(), RecordType::SOA);
+        assert_eq!(nxdomain.authority[0].ttl, 300);
+
+        let nodata = ask(&authority, "www.example.com.", RecordType::MX).unwrap();
+        assert_eq!(nodata.rcode, RCODE::NoErr);
+        assert!(nodata.answer.is_empty());
+        assert_eq!(nodata.authority[0].rtype(), RecordType::SOA);
+
+        // An empty non-terminal exists, it just has no records
+        let ent = ask(&authority, "b.ent.example.com.", RecordType::A).unwrap();
+        assert_eq!(ent.rcode, RCODE::NoErr);
+        assert!(ent.answer.is_empty());
+    }
+
+    #[test]
+    fn referral_with_glue() {
+        let authority = authority("referral", ZONE);
+        let answer = ask(&authority, "www.sub.example.com.", RecordType::A).unwrap();
+
+        assert_eq!(answer.rcode, RCODE::NoErr);
+        assert!(!answer.authoritative);
+        assert!(answer.answer.is_empty());
+        assert_eq!(
+            answer.authority[0].rdata,
+            RData::NS("ns.sub.example.com.".parse().unwrap())
+        );
+        assert_eq!(answer.additional[0].rdata, RData::A([192, 0, 2, 53].into()));
+    }
+
+    #[test]
+    fn refuses_names_outside_our_zones() {
+        let authority = authority("refuse", ZONE);
+
+        assert!(ask(&authority, "example.org.", RecordType::A).is_none());
+    }
+
+    #[test]
+    fn wildcard_answers_as_the_query_name() {
+        let authority = authority("wildcard", WILDCARDS);
+
+        let answer = ask(&authority, "x.y.preview.example.com.", RecordType::A).unwrap();
+        assert_eq!(answer.rcode, RCODE::NoErr);
+        assert!(answer.authoritative);
+        assert_eq!(answer.answer.len(), 1);
+        assert_eq!(
+            answer.answer[0].name,
+            "x.y.preview.example.com.".parse().unwrap()
+        );
+        assert_eq!(answer.answer[0].rdata, RData::A([192, 0, 2, 200].into()));
+
+        let any = ask(&authority, "pr1.preview.example.com.", ANY).unwrap();
+        assert_eq!(any.answer.len(), 2);
+
+        // The wildcard owns no MX, so there is no data of that type
+        let nodata = ask(&authority, "pr1.preview.example.com.", RecordType::MX).unwrap();
+        assert_eq!(nodata.rcode, RCODE::NoErr);
+        assert!(nodata.answer.is_empty());
+        assert_eq!(nodata.authority[0].rtype(), RecordType::SOA);
+    }
+
+    #[test]
+    fn names_that_exist_are_not_wildcarded() {
+        let authority = authority("exists", WILDCARDS);
+
+        let answer = ask(&authority, "host.preview.example.com.", RecordType::TXT).unwrap();
+        assert_eq!(answer.rcode, RCODE::NoErr);
+        assert!(answer.answer.is_empty());
+    }
+
+    #[test]
+    fn empty_non_terminal_blocks_the_wildcard() {
+        let authority = authority("ent", WILDCARDS);
+
+        let answer = ask(&authority, "b.ent.example.com.", RecordType::TXT).unwrap();
+        assert_eq!(answer.rcode, RCODE::NoErr);
+        assert!(answer.answer.is_empty());
+    }
+
+    #[test]
+    fn nxdomain_below_an_empty_non_terminal() {
+        let authority = authority("encloser", WILDCARDS);
+
+        // The closest encloser is ent.example.com, which has no wildcard of
+        // its own, so the one at the apex doesn't apply
+        let answer = ask(&authority, "z.ent.example.com.", RecordType::TXT).unwrap();
+        assert_eq!(answer.rcode, RCODE::NameErr);
+        assert!(answer.answer.is_empty());
+
+        let apex = ask(&authority, "z.example.com.", RecordType::TXT).unwrap();
+        assert_eq!(apex.rcode, RCODE::NoErr);
+        assert_eq!(apex.answer[0].name, "z.example.com.".parse().unwrap());
+    }
+
+    #[test]
+    fn wildcard_cname_is_followed() {
+        let authority = authority("cname", WILDCARDS);
+        let answer = ask(&authority, "foo.alias.example.com.", RecordType::A).unwrap();
+
+        assert_eq!(
+            answer.answer[0].name,
+            "foo.alias.example.com.".parse().unwrap()
+        );
+        assert_eq!(
+            answer.answer[0].rdata,
+            RData::CNAME("www.example.com.".parse().unwrap())
+        );
+        assert_eq!(answer.answer[1].rdata, RData::A([192, 0, 2, 80].into()));
+    }
+
+    #[test]
+    fn no_wildcard_below_a_zone_cut() {
+        let authority = authority("cut", WILDCARDS);
+        let answer = ask(&authority, "x.sub.example.com.", RecordType::TXT).unwrap();
+
+        assert!(!answer.authoritative);
+        assert!(answer.answer.is_empty());
+        assert_eq!(answer.authority[0].rtype(), RecordType::NS);
+    }
+}
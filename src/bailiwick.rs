@@ -0,0 +1,326 @@
+use std::collections::HashSet;
+
+use crate::{
+    name::DomainName,
+    rdata::{RData, RecordType},
+    DNSMessage, DNSQuery, DNSResource,
+};
+
+/// Most CNAMEs followed when working out which names an answer may cover
+const MAX_CHAIN: usize = 16;
+
+/// DNSSEC types we pass through without decoding, RFC 4034 and RFC 5155
+const RRSIG: RecordType = RecordType::Unknown(46);
+const NSEC: RecordType = RecordType::Unknown(47);
+const NSEC3: RecordType = RecordType::Unknown(50);
+
+/// Throw away anything in `response` that a server asked about `query` had
+/// no business telling us
+///
+/// `bailiwick` is the zone the server was asked as an authority for, or the
+/// zone of the forwarding rule that sent the query there. Nothing outside
+/// it is kept, and within it:
+///
+/// - answers have to belong to the name asked about or a name its CNAME
+///   chain leads to
+/// - authority records have to be for the name or a zone above it, except
+///   the NSEC and NSEC3 records proving a negative answer, which belong to
+///   neighbouring names
+/// - additional records are only kept as glue for the name servers in the
+///   authority section, along with their signatures
+///
+/// When the CNAME chain leads out of the bailiwick the target it was cut
+/// at is returned, so it can be looked up from a server that answers for
+/// it.
+pub fn scrub(
+    response: &mut DNSMessage,
+    query: &DNSQuery,
+    bailiwick: &DomainName,
+) -> Option<DomainName> {
+    let (chain, cut) = cname_chain(&response.ans, &query.qname, bailiwick);
+    let last = chain.last().cloned().unwrap_or_else(|| query.qname.clone());
+
+    retain(&mut response.ans, |rr| chain.contains(&rr.name));
+    retain(&mut response.nsr, |rr| {
+        let denial = matches!(signed_type(rr), NSEC | NSEC3);
+        rr.name.is_subdomain_of(bailiwick) && (denial || last.is_subdomain_of(&rr.name))
+    });
+
+    let name_servers = response
+        .nsr
+        .iter()
+        .filter_map(|rr| match &rr.rdata {
+            RData::NS(name) => Some(name.clone()),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+
+    retain(&mut response.arc, |rr| {
+        matches!(signed_type(rr), RecordType::A | RecordType::AAAA)
+            && name_servers.contains(&rr.name)
+            && rr.name.is_subdomain_of(bailiwick)
+    });
+
+    cut
+}
+
+/// The name asked about followed by every name its CNAMEs lead to, as far
+/// as `records` go without leaving `bailiwick`, and the target outside it
+/// where the chain stopped if it did
+fn cname_chain(
+    records: &[DNSResource],
+    qname: &DomainName,
+    bailiwick: &DomainName,
+) -> (Vec<DomainName>, Option<DomainName>) {
+    let mut chain = vec![];
+    let mut name = qname.clone();
+
+    while !chain.contains(&name) && chain.len() < MAX_CHAIN {
+        if !name.is_subdomain_of(bailiwick) {
+            return (chain, Some(name));
+        }
+        chain.push(name.clone());
+
+        let target = records.iter().find_map(|rr| match &rr.rdata {
+            RData::CNAME(target) if rr.name == name => Some(target.clone()),
+            _ => None,
+        });
+
+        match target {
+            Some(target) => name = target,
+            None => break,
+        }
+    }
+
+    (chain, None)
+}
+
+/// The type of the record, or for an RRSIG the type it covers, so a
+/// signature is kept or dropped along with what it signs
+fn signed_type(rr: &DNSResource) -> RecordType {
+    match &rr.rdata {
+        RData::Unknown { bytes, .. } if rr.rtype() == RRSIG && bytes.len() >= 2 => {
+            RecordType::from_wire(u16::from_be_bytes([bytes[0], bytes[1]]))
+        }
+        _ => rr.rtype(),
+    }
+}
+
+fn retain(records: &mut Vec<DNSResource>, keep: impl Fn(&DNSResource) -> bool) {
+    records.retain(|rr| {
+        let kept = keep(rr);
+        if !kept {
+            println!("Dropping out of bailiwick record {}", rr);
+        }
+
+        kept
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdata::RecordClass;
+
+    fn name(s: &str) -> DomainName {
+        s.parse().unwrap()
+    }
+
+    fn rr(owner: &str, rdata: RData) -> DNSResource {
+        DNSResource {
+            name: name(owner),
+            class: RecordClass::IN,
+            ttl: 300,
+            rdata,
+        }
+    }
+
+    fn a(owner: &str) -> DNSResource {
+        rr(owner, RData::A([192, 0, 2, 1].into()))
+    }
+
+    fn ns(owner: &str, target: &str) -> DNSResource {
+        rr(owner, RData::NS(name(target)))
+    }
+
+    fn cname(owner: &str, target: &str) -> DNSResource {
+        rr(owner, RData::CNAME(name(target)))
+    }
+
+    /// A record of a type we don't decode, with `covered` at the front as
+    /// an RRSIG has it
+    fn opaque(owner: &str, rtype: RecordType, covered: RecordType) -> DNSResource {
+        let mut bytes = covered.to_wire().to_be_bytes().to_vec();
+        bytes.extend_from_slice(&[0; 16]);
+
+        rr(
+            owner,
+            RData::Unknown {
+                rtype: rtype.to_wire(),
+                bytes,
+            },
+        )
+    }
+
+    fn scrubbed(
+        qname: &str,
+        ans: Vec<DNSResource>,
+        nsr: Vec<DNSResource>,
+        arc: Vec<DNSResource>,
+    ) -> DNSMessage {
+        let query = DNSQuery {
+            qname: name(qname),
+            ..DNSQuery::shell()
+        };
+
+        let mut response = DNSMessage::new();
+        response.ans = ans;
+        response.nsr = nsr;
+        response.arc = arc;
+        scrub(&mut response, &query, &name("corp.example."));
+
+        response
+    }
+
+    fn owners(records: &[DNSResource]) -> Vec<String> {
+        records.iter().map(|rr| rr.name.to_string()).collect()
+    }
+
+    #[test]
+    fn drops_answers_outside_the_bailiwick() {
+        let response = scrubbed(
+            "www.corp.example.",
+            vec![a("www.corp.example."), a("www.evil.example.")],
+            vec![],
+            vec![],
+        );
+
+        assert_eq!(owners(&response.ans), ["www.corp.example."]);
+    }
+
+    #[test]
+    fn keeps_answers_along_the_cname_chain() {
+        let response = scrubbed(
+            "a.corp.example.",
+            vec![
+                cname("a.corp.example.", "b.corp.example."),
+                cname("b.corp.example.", "c.corp.example."),
+                a("c.corp.example."),
+                a("unrelated.corp.example."),
+            ],
+            vec![],
+            vec![],
+        );
+
+        assert_eq!(
+            owners(&response.ans),
+            ["a.corp.example.", "b.corp.example.", "c.corp.example."]
+        );
+    }
+
+    #[test]
+    fn cuts_the_chain_where_it_leaves_the_bailiwick() {
+        let response = scrubbed(
+            "a.corp.example.",
+            vec![
+                cname("a.corp.example.", "www.evil.example."),
+                a("www.evil.example."),
+                cname("www.evil.example.", "b.corp.example."),
+                a("b.corp.example."),
+            ],
+            vec![],
+            vec![],
+        );
+
+        assert_eq!(owners(&response.ans), ["a.corp.example."]);
+    }
+
+    #[test]
+    fn reports_where_the_chain_was_cut() {
+        let query = DNSQuery {
+            qname: name("a.corp.example."),
+            ..DNSQuery::shell()
+        };
+        let bailiwick = name("corp.example.");
+
+        let mut response = DNSMessage::new();
+        response.ans = vec![cname("a.corp.example.", "www.cdn.example.")];
+        assert_eq!(
+            scrub(&mut response, &query, &bailiwick),
+            Some(name("www.cdn.example."))
+        );
+
+        response.ans = vec![
+            cname("a.corp.example.", "b.corp.example."),
+            a("b.corp.example."),
+        ];
+        assert_eq!(scrub(&mut response, &query, &bailiwick), None);
+    }
+
+    #[test]
+    fn keeps_authority_for_the_name_and_denial_records() {
+        let response = scrubbed(
+            "missing.corp.example.",
+            vec![],
+            vec![
+                rr(
+                    "corp.example.",
+                    RData::SOA {
+                        mname: name("ns1.corp.example."),
+                        rname: name("hostmaster.corp.example."),
+                        serial: 1,
+                        refresh: 2,
+                        retry: 3,
+                        expire: 4,
+                        minimum: 5,
+                    },
+                ),
+                opaque("lost.corp.example.", NSEC, NSEC),
+                opaque("lost.corp.example.", RRSIG, NSEC),
+                opaque("h4sh.corp.example.", NSEC3, NSEC3),
+                // not above the name asked about
+                ns("other.corp.example.", "ns1.corp.example."),
+                opaque("lost.evil.example.", NSEC, NSEC),
+            ],
+            vec![],
+        );
+
+        assert_eq!(
+            owners(&response.nsr),
+            [
+                "corp.example.",
+                "lost.corp.example.",
+                "lost.corp.example.",
+                "h4sh.corp.example."
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_glue_only_for_name_servers() {
+        let response = scrubbed(
+            "www.sub.corp.example.",
+            vec![],
+            vec![
+                ns("sub.corp.example.", "ns1.corp.example."),
+                ns("sub.corp.example.", "ns.evil.example."),
+            ],
+            vec![
+                a("ns1.corp.example."),
+                opaque("ns1.corp.example.", RRSIG, RecordType::A),
+                // signs something other than the address
+                opaque("ns1.corp.example.", RRSIG, RecordType::TXT),
+                a("ns.evil.example."),
+                a("www.corp.example."),
+                opaque("www.corp.example.", RRSIG, RecordType::A),
+            ],
+        );
+
+        assert_eq!(response.nsr.len(), 2);
+        assert_eq!(
+            owners(&response.arc),
+            ["ns1.corp.example.", "ns1.corp.example."]
+        );
+        assert_eq!(signed_type(&response.arc[1]), RecordType::A);
+    }
+}
@@ -0,0 +1,536 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Mutex,
+    time::Instant,
+};
+
+use crate::{
+    config::Config,
+    name::DomainName,
+    rdata::{RData, RecordClass, RecordType},
+    DNSMessage, DNSQuery, DNSResource, RCODE,
+};
+
+/// Longest we keep anything, whatever TTL it came with
+const MAX_TTL: u32 = 86400;
+
+/// The question, and whether it was asked with the DO bit set. Answers
+/// fetched without DO have no signatures in them, so can't be handed to a
+/// client that wants to validate
+type Key = (DomainName, RecordType, RecordClass, bool);
+
+fn key(query: &DNSQuery, dnssec_ok: bool) -> Key {
+    (query.qname.clone(), query.qtype, query.qclass, dnssec_ok)
+}
+
+/// What the cache hands back for a question
+///
+/// For NXDOMAIN and NODATA answers, RFC 2308, `authority` holds the SOA
+/// record of the zone that said so, and `records` anything that came before
+/// it, such as a CNAME leading into that zone.
+#[derive(Debug, Clone)]
+pub struct Answer {
+    pub rcode: RCODE,
+    pub records: Vec<DNSResource>,
+    pub authority: Vec<DNSResource>,
+}
+
+impl Answer {
+    /// Work out what to keep from an upstream response, and for how long
+    ///
+    /// Errors other than NXDOMAIN aren't kept, nor are negative answers
+    /// without an SOA to take their TTL from.
+    fn from_response(response: &DNSMessage) -> Option<(Answer, u32)> {
+        if response.header.tc {
+            return None;
+        }
+
+        let records = response.ans.clone();
+        let ttls = records.iter().map(|rr| rr.ttl);
+
+        let positive = response.header.rcode == RCODE::NoErr && !records.is_empty();
+        if positive {
+            let ttl = ttls.min()?;
+
+            return Some((
+                Answer {
+                    rcode: RCODE::NoErr,
+                    records,
+                    authority: vec![],
+                },
+                ttl,
+            ));
+        }
+
+        if !matches!(response.header.rcode, RCODE::NoErr | RCODE::NameErr) {
+            return None;
+        }
+
+        // A negative answer lasts for the lower of the SOA's own TTL and its
+        // MINIMUM field, RFC 2308 section 5
+        let (soa, minimum) = response.nsr.iter().find_map(|rr| match rr.rdata {
+            RData::SOA { minimum, .. } => Some((rr, minimum)),
+            _ => None,
+        })?;
+        let ttl = ttls.chain([soa.ttl, minimum]).min()?;
+
+        let soa = DNSResource { ttl, ..soa.clone() };
+
+        Some((
+            Answer {
+                rcode: response.header.rcode.clone(),
+                records,
+                authority: vec![soa],
+            },
+            ttl,
+        ))
+    }
+}
+
+/// The answer to one question, as it was when we stored it
+#[derive(Debug)]
+struct Entry {
+    answer: Answer,
+    stored: Instant,
+    /// Seconds the whole answer is good for, the lowest TTL in it
+    ttl: u32,
+    /// When the entry was last used, for picking what to evict
+    used: u64,
+}
+
+impl Entry {
+    fn age(&self) -> u32 {
+        self.stored.elapsed().as_secs().min(u32::MAX as u64) as u32
+    }
+
+    fn is_expired(&self) -> bool {
+        self.age() >= self.ttl
+    }
+
+    /// Whether the entry is too old even to be served stale
+    fn is_gone(&self, stale_window: u32) -> bool {
+        self.age() >= self.ttl.saturating_add(stale_window)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    map: HashMap<Key, Entry>,
+    /// Keys by when they were last used, oldest first
+    lru: BTreeMap<u64, Key>,
+    clock: u64,
+    /// Questions being looked up again in the background
+    refreshing: HashSet<Key>,
+}
+
+impl Entries {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.map.remove(key) {
+            self.lru.remove(&entry.used);
+        }
+    }
+}
+
+/// Answers we have already had from upstream, keyed on the question and
+/// the DO bit it was asked with
+///
+/// Both records and the news that there aren't any are kept. Records go
+/// back out with their TTLs counted down by the time they have spent here,
+/// and the whole answer goes once its shortest TTL runs out.
+/// When the cache is full, expired answers are dropped first and then the
+/// ones used least recently.
+///
+/// Expired answers are held on to for `stale_window` seconds longer, to
+/// fall back on when upstream can't be reached, RFC 8767.
+#[derive(Debug)]
+pub struct Cache {
+    capacity: usize,
+    stale_window: u32,
+    stale_ttl: u32,
+    entries: Mutex<Entries>,
+}
+
+impl Cache {
+    pub fn new(config: &Config) -> Cache {
+        Cache {
+            capacity: config.cache_size,
+            stale_window: config.stale_window,
+            stale_ttl: config.stale_ttl,
+            entries: Mutex::default(),
+        }
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, Entries> {
+        self.entries.lock().expect("cache lock poisoned")
+    }
+
+    /// The cached answer to `query`, if there is one still in date
+    pub fn get(&self, query: &DNSQuery, dnssec_ok: bool) -> Option<Answer> {
+        self.lookup(query, dnssec_ok, false)
+    }
+
+    /// An answer to `query` that has expired but is still inside the stale
+    /// window, with every TTL set to the stale answer TTL
+    pub fn get_stale(&self, query: &DNSQuery, dnssec_ok: bool) -> Option<Answer> {
+        self.lookup(query, dnssec_ok, true)
+    }
+
+    fn lookup(&self, query: &DNSQuery, dnssec_ok: bool, stale: bool) -> Option<Answer> {
+        let key = key(query, dnssec_ok);
+        let mut entries = self.entries();
+
+        let entry = entries.map.get(&key)?;
+        if entry.is_gone(self.stale_window) {
+            entries.remove(&key);
+            return None;
+        }
+        if entry.is_expired() != stale {
+            return None;
+        }
+
+        let used = entries.tick();
+        let entry = entries.map.get_mut(&key)?;
+        let old = std::mem::replace(&mut entry.used, used);
+        let age = entry.age();
+
+        let age_records = |records: &[DNSResource]| {
+            records
+                .iter()
+                .map(|rr| {
+                    let mut rr = rr.clone();
+                    rr.ttl = if stale {
+                        self.stale_ttl
+                    } else {
+                        rr.ttl.saturating_sub(age)
+                    };
+
+                    // Answer in the client's spelling of the name
+                    if rr.name == query.qname {
+                        rr.name = query.qname.clone();
+                    }
+
+                    rr
+                })
+                .collect()
+        };
+
+        let answer = Answer {
+            rcode: entry.answer.rcode.clone(),
+            records: age_records(&entry.answer.records),
+            authority: age_records(&entry.answer.authority),
+        };
+
+        entries.lru.remove(&old);
+        entries.lru.insert(used, key);
+
+        Some(answer)
+    }
+
+    /// Store what upstream said in `response` about `query`, asked with DO
+    /// set or not as `dnssec_ok` says
+    ///
+    /// Anything with a TTL of zero isn't kept.
+    pub fn insert(&self, query: &DNSQuery, dnssec_ok: bool, response: &DNSMessage) {
+        let Some((mut answer, ttl)) = Answer::from_response(response) else {
+            return;
+        };
+        let ttl = ttl.min(MAX_TTL);
+        if ttl == 0 || self.capacity == 0 {
+            return;
+        }
+
+        for rr in answer.records.iter_mut().chain(answer.authority.iter_mut()) {
+            rr.ttl = rr.ttl.min(MAX_TTL);
+        }
+
+        let key = key(query, dnssec_ok);
+        let mut entries = self.entries();
+        entries.remove(&key);
+
+        if entries.map.len() >= self.capacity {
+            entries
+                .map
+                .retain(|_, entry| !entry.is_gone(self.stale_window));
+
+            let Entries { map, lru, .. } = &mut *entries;
+            lru.retain(|_, key| map.contains_key(key));
+        }
+
+        while entries.map.len() >= self.capacity {
+            let Some((_, oldest)) = entries.lru.pop_first() else {
+                break;
+            };
+            entries.map.remove(&oldest);
+        }
+
+        let used = entries.tick();
+        entries.lru.insert(used, key.clone());
+        entries.map.insert(
+            key,
+            Entry {
+                answer,
+                stored: Instant::now(),
+                ttl,
+                used,
+            },
+        );
+    }
+
+    /// Note that `query` is being looked up again, false if that is already
+    /// under way
+    pub fn start_refresh(&self, query: &DNSQuery, dnssec_ok: bool) -> bool {
+        self.entries().refreshing.insert(key(query, dnssec_ok))
+    }
+
+    pub fn finish_refresh(&self, query: &DNSQuery, dnssec_ok: bool) {
+        self.entries().refreshing.remove(&key(query, dnssec_ok));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn cache(capacity: usize) -> Cache {
+        Cache::new(&Config {
+            cache_size: capacity,
+            ..Config::default()
+        })
+    }
+
+    fn query(name: &str) -> DNSQuery {
+        DNSQuery {
+            qname: name.parse().unwrap(),
+            ..DNSQuery::shell()
+        }
+    }
+
+    fn a(name: &str, ttl: u32) -> DNSResource {
+        DNSResource {
+            name: name.parse().unwrap(),
+            ttl,
+            rdata: RData::A([192, 0, 2, 1].into()),
+            ..DNSResource::shell()
+        }
+    }
+
+    fn response(rcode: RCODE, ans: Vec<DNSResource>, nsr: Vec<DNSResource>) -> DNSMessage {
+        let mut message = DNSMessage::new();
+        message.header.qr = true;
+        message.header.rcode = rcode;
+        message.ans = ans;
+        message.nsr = nsr;
+
+        message
+    }
+
+    /// A positive answer of one A record
+    fn positive(name: &str, ttl: u32) -> DNSMessage {
+        response(RCODE::NoErr, vec![a(name, ttl)], vec![])
+    }
+
+    fn soa(ttl: u32, minimum: u32) -> DNSResource {
+        DNSResource {
+            name: "example.com.".parse().unwrap(),
+            ttl,
+            rdata: RData::SOA {
+                mname: "ns.example.com.".parse().unwrap(),
+                rname: "hostmaster.example.com.".parse().unwrap(),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum,
+            },
+            ..DNSResource::shell()
+        }
+    }
+
+    /// Make the cached answer to `query` look `secs` seconds older
+    fn wait(cache: &Cache, query: &DNSQuery, dnssec_ok: bool, secs: u64) {
+        let mut entries = cache.entries();
+        let entry = entries.map.get_mut(&key(query, dnssec_ok)).unwrap();
+        entry.stored = entry.stored.checked_sub(Duration::from_secs(secs)).unwrap();
+    }
+
+    fn ttls(answer: &Answer) -> Vec<u32> {
+        answer.records.iter().map(|rr| rr.ttl).collect()
+    }
+
+    #[test]
+    fn ttls_count_down_until_the_answer_expires() {
+        let cache = cache(10);
+        let q = query("example.com.");
+        let ans = vec![a("example.com.", 300), a("example.com.", 100)];
+        cache.insert(&q, false, &response(RCODE::NoErr, ans, vec![]));
+
+        assert_eq!(ttls(&cache.get(&q, false).unwrap()), [300, 100]);
+
+        wait(&cache, &q, false, 40);
+        assert_eq!(ttls(&cache.get(&q, false).unwrap()), [260, 60]);
+
+        // the whole answer goes with its shortest TTL
+        wait(&cache, &q, false, 60);
+        assert!(cache.get(&q, false).is_none());
+    }
+
+    #[test]
+    fn ttls_are_capped_and_zero_is_not_kept() {
+        let cache = cache(10);
+        let long = query("long.example.");
+        let zero = query("zero.example.");
+        cache.insert(&long, false, &positive("long.example.", 1 << 30));
+        cache.insert(&zero, false, &positive("zero.example.", 0));
+
+        assert_eq!(ttls(&cache.get(&long, false).unwrap()), [MAX_TTL]);
+        assert!(cache.get(&zero, false).is_none());
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let cache = cache(2);
+        let [one, two, three] = ["one.example.", "two.example.", "three.example."].map(query);
+        for q in [&one, &two] {
+            cache.insert(q, false, &positive(&q.qname.to_string(), 300));
+        }
+
+        // using `one` leaves `two` as the oldest
+        assert!(cache.get(&one, false).is_some());
+        cache.insert(&three, false, &positive("three.example.", 300));
+
+        assert!(cache.get(&one, false).is_some());
+        assert!(cache.get(&two, false).is_none());
+        assert!(cache.get(&three, false).is_some());
+    }
+
+    #[test]
+    fn keyed_on_the_do_bit() {
+        let cache = cache(10);
+        let q = query("example.com.");
+        cache.insert(&q, true, &positive("example.com.", 300));
+
+        assert!(cache.get(&q, true).is_some());
+        assert!(cache.get(&q, false).is_none());
+    }
+
+    #[test]
+    fn answers_in_the_clients_spelling() {
+        let cache = cache(10);
+        let stored = query("example.com.");
+        cache.insert(&stored, false, &positive("example.com.", 300));
+
+        let asked = query("ExAmple.COM.");
+        let answer = cache.get(&asked, false).unwrap();
+        assert!(answer.records[0].name.eq_exact(&asked.qname));
+    }
+
+    #[test]
+    fn negative_ttl_is_the_lower_of_soa_ttl_and_minimum() {
+        let cache = cache(10);
+        let [low_ttl, low_minimum] = ["a.example.com.", "b.example.com."].map(query);
+        cache.insert(
+            &low_ttl,
+            false,
+            &response(RCODE::NameErr, vec![], vec![soa(60, 600)]),
+        );
+        cache.insert(
+            &low_minimum,
+            false,
+            &response(RCODE::NameErr, vec![], vec![soa(600, 60)]),
+        );
+
+        for q in [&low_ttl, &low_minimum] {
+            let answer = cache.get(q, false).unwrap();
+            assert_eq!(answer.authority[0].ttl, 60);
+
+            wait(&cache, q, false, 60);
+            assert!(cache.get(q, false).is_none());
+        }
+    }
+
+    #[test]
+    fn replays_nxdomain_and_nodata() {
+        let cache = cache(10);
+        let [nxdomain, nodata] = ["missing.example.com.", "example.com."].map(query);
+        cache.insert(
+            &nxdomain,
+            false,
+            &response(RCODE::NameErr, vec![], vec![soa(300, 300)]),
+        );
+        cache.insert(
+            &nodata,
+            false,
+            &response(RCODE::NoErr, vec![], vec![soa(300, 300)]),
+        );
+
+        let answer = cache.get(&nxdomain, false).unwrap();
+        assert_eq!(answer.rcode, RCODE::NameErr);
+        assert!(answer.records.is_empty());
+        assert_eq!(answer.authority[0].rtype(), RecordType::SOA);
+
+        wait(&cache, &nodata, false, 100);
+        let answer = cache.get(&nodata, false).unwrap();
+        assert_eq!(answer.rcode, RCODE::NoErr);
+        assert!(answer.records.is_empty());
+        assert_eq!(answer.authority[0].ttl, 200);
+    }
+
+    #[test]
+    fn negative_answers_need_an_soa() {
+        let cache = cache(10);
+        let [missing, broken] = ["missing.example.com.", "broken.example.com."].map(query);
+        cache.insert(&missing, false, &response(RCODE::NameErr, vec![], vec![]));
+        cache.insert(
+            &broken,
+            false,
+            &response(RCODE::ServerFail, vec![], vec![soa(300, 300)]),
+        );
+
+        assert!(cache.get(&missing, false).is_none());
+        assert!(cache.get(&broken, false).is_none());
+    }
+
+    #[test]
+    fn serves_stale_inside_the_window() {
+        let cache = Cache::new(&Config {
+            stale_window: 600,
+            stale_ttl: 30,
+            ..Config::default()
+        });
+        let q = query("example.com.");
+        cache.insert(&q, false, &positive("example.com.", 300));
+
+        // nothing stale about it yet
+        assert!(cache.get_stale(&q, false).is_none());
+
+        wait(&cache, &q, false, 300);
+        assert!(cache.get(&q, false).is_none());
+        assert_eq!(ttls(&cache.get_stale(&q, false).unwrap()), [30]);
+
+        wait(&cache, &q, false, 599);
+        assert!(cache.get_stale(&q, false).is_some());
+
+        wait(&cache, &q, false, 1);
+        assert!(cache.get_stale(&q, false).is_none());
+        assert!(cache.entries().map.is_empty());
+    }
+
+    #[test]
+    fn one_refresh_at_a_time() {
+        let cache = cache(10);
+        let q = query("example.com.");
+
+        assert!(cache.start_refresh(&q, false));
+        assert!(!cache.start_refresh(&q, false));
+        assert!(cache.start_refresh(&q, true));
+
+        cache.finish_refresh(&q, false);
+        assert!(cache.start_refresh(&q, false));
+    }
+}
@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use crate::{
+    authority::ZoneSource, forward::ForwardRule, recursor::QnameMinimisation, resolvers::Strategy,
+};
+
+/// Reasons the command line can be rejected
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("{0} needs a value")]
+    MissingValue(String),
+    #[error("invalid value {value:?} for {flag}")]
+    BadValue { flag: String, value: String },
+    #[error("unknown argument {0}")]
+    UnknownArgument(String),
+}
+
+/// Settings taken from the command line
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Resolvers queries are forwarded to, `--resolver <addr>[,<addr>...]`,
+    /// which may also be given more than once
+    pub resolvers: Vec<String>,
+    /// How to pick between the resolvers, `--strategy <name>`
+    pub strategy: Strategy,
+    /// Zones sent somewhere other than `resolvers`, `--forward <rule>`
+    pub forwards: Vec<ForwardRule>,
+    /// Resolve names ourselves from the root down instead of forwarding
+    /// them to `resolvers`, `--recursive`
+    pub recursive: bool,
+    /// Zones to answer for ourselves, `--zone <origin>=<path>`. With any of
+    /// these we only serve them and refuse everything else
+    pub zones: Vec<ZoneSource>,
+    /// File listing the root servers, `--root-hints <path>`
+    pub root_hints: String,
+    /// Port to ask authoritative servers on when recursing, `--auth-port <n>`
+    pub auth_port: u16,
+    /// How much of a name to show the servers above it when recursing,
+    /// `--qname-minimisation <off|strict|relaxed>`
+    pub qname_minimisation: QnameMinimisation,
+    /// Randomise the case of forwarded query names, `--0x20`
+    pub use_0x20: bool,
+    /// How long to wait on the first attempt of an upstream query,
+    /// `--timeout <ms>`. Each retry waits twice as long as the one before
+    pub timeout: Duration,
+    /// How many times to resend an unanswered upstream query, `--retries <n>`
+    pub retries: u32,
+    /// Most answers to keep in the cache, `--cache-size <n>`. Zero turns the
+    /// cache off
+    pub cache_size: usize,
+    /// Seconds past expiry an answer may still be served when upstream
+    /// can't be reached, `--stale-window <secs>`. Zero turns serve-stale off
+    pub stale_window: u32,
+    /// TTL given to records served stale, `--stale-ttl <secs>`
+    pub stale_ttl: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            resolvers: vec!["8.8.8.8:53".to_string()],
+            strategy: Strategy::Failover,
+            forwards: vec![],
+            recursive: false,
+            zones: vec![],
+            root_hints: "root.hints".to_string(),
+            auth_port: 53,
+            qname_minimisation: QnameMinimisation::Relaxed,
+            use_0x20: false,
+            timeout: Duration::from_millis(2000),
+            retries: 2,
+            cache_size: 10000,
+            stale_window: 86400,
+            stale_ttl: 30,
+        }
+    }
+}
+
+impl Config {
+    /// Build the configuration from the program's arguments, without the
+    /// program name
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        let mut args = args.into_iter();
+        let mut resolvers = vec![];
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| ConfigError::MissingValue(arg.clone()))
+            };
+
+            match arg.as_str() {
+                "--resolver" => {
+                    let list = value()?;
+                    if list.split(',').any(str::is_empty) {
+                        return Err(ConfigError::BadValue {
+                            flag: arg,
+                            value: list,
+                        });
+                    }
+
+                    resolvers.extend(list.split(',').map(str::to_string));
+                }
+                "--strategy" => config.strategy = parse(&arg, value()?)?,
+                "--forward" => config.forwards.push(parse(&arg, value()?)?),
+                "--recursive" => config.recursive = true,
+                "--zone" => config.zones.push(parse(&arg, value()?)?),
+                "--root-hints" => config.root_hints = value()?,
+                "--auth-port" => config.auth_port = parse(&arg, value()?)?,
+                "--qname-minimisation" => config.qname_minimisation = parse(&arg, value()?)?,
+                "--0x20" => config.use_0x20 = true,
+                "--timeout" => {
+                    // Sockets take a zero timeout as an error, or as no
+                    // time to wait at all
+                    let value = value()?;
+                    let ms = match parse(&arg, value.clone())? {
+                        0 => return Err(ConfigError::BadValue { flag: arg, value }),
+                        ms => ms,
+                    };
+                    config.timeout = Duration::from_millis(ms);
+                }
+                "--retries" => config.retries = parse(&arg, value()?)?,
+                "--cache-size" => config.cache_size = parse(&arg, value()?)?,
+                "--stale-window" => config.stale_window = parse(&arg, value()?)?,
+                "--stale-ttl" => config.stale_ttl = parse(&arg, value()?)?,
+                _ => return Err(ConfigError::UnknownArgument(arg)),
+            }
+        }
+
+        if !resolvers.is_empty() {
+            config.resolvers = resolvers;
+        }
+
+        Ok(config)
+    }
+}
+
+/// Parse the value given for `flag`
+fn parse<T: std::str::FromStr>(flag: &str, value: String) -> Result<T, ConfigError> {
+    value.parse().map_err(|_| ConfigError::BadValue {
+        flag: flag.to_string(),
+        value,
+    })
+}
@@ -0,0 +1,423 @@
+use std::fmt;
+
+use crate::{
+    name::DomainName,
+    rdata::{RData, RecordClass},
+    BytePacketBuffer, DNSResource,
+};
+
+/// UDP payload size we advertise, the figure agreed on for DNS flag day 2020
+pub const EDNS_PAYLOAD_SIZE: u16 = 1232;
+
+/// Extended RCODE telling a client we don't speak its EDNS version
+pub const BADVERS: u8 = 1;
+
+/// Extended DNS error code for an answer served from expired cache data,
+/// RFC 8914 section 4.4
+pub const EDE_STALE_ANSWER: u16 = 3;
+
+/// The DO bit in the EDNS flags, RFC 3225
+const DNSSEC_OK: u16 = 0x8000;
+
+/// A single option carried in the OPT record's RDATA
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EdnsOption {
+    /// Name server identifier, RFC 5001
+    Nsid(Vec<u8>),
+    /// Client subnet, RFC 7871
+    ClientSubnet {
+        family: u16,
+        source_prefix: u8,
+        scope_prefix: u8,
+        address: Vec<u8>,
+    },
+    /// DNS cookies, RFC 7873
+    Cookie { client: Vec<u8>, server: Vec<u8> },
+    /// TCP keepalive in units of 100 milliseconds, RFC 7828
+    TcpKeepalive(Option<u16>),
+    /// Padding of the given length, RFC 7830
+    Padding(u16),
+    /// Extended DNS error, RFC 8914
+    ExtendedError { info_code: u16, extra_text: String },
+    /// An option we don't know, kept as received
+    Unknown { code: u16, data: Vec<u8> },
+}
+
+impl EdnsOption {
+    pub fn code(&self) -> u16 {
+        match self {
+            EdnsOption::Nsid(_) => 3,
+            EdnsOption::ClientSubnet { .. } => 8,
+            EdnsOption::Cookie { .. } => 10,
+            EdnsOption::TcpKeepalive(_) => 11,
+            EdnsOption::Padding(_) => 12,
+            EdnsOption::ExtendedError { .. } => 15,
+            EdnsOption::Unknown { code, .. } => *code,
+        }
+    }
+
+    /// Decode the data of one option, falling back to `Unknown` for codes we
+    /// don't know or data which doesn't fit the option's layout
+    pub fn from_wire(code: u16, data: &[u8]) -> EdnsOption {
+        let decoded = match code {
+            3 => Some(EdnsOption::Nsid(data.to_vec())),
+            8 if data.len() >= 4 => Some(EdnsOption::ClientSubnet {
+                family: u16::from_be_bytes([data[0], data[1]]),
+                source_prefix: data[2],
+                scope_prefix: data[3],
+                address: data[4..].to_vec(),
+            }),
+            10 if data.len() == 8 || (16..=40).contains(&data.len()) => Some(EdnsOption::Cookie {
+                client: data[..8].to_vec(),
+                server: data[8..].to_vec(),
+            }),
+            11 if data.is_empty() => Some(EdnsOption::TcpKeepalive(None)),
+            11 if data.len() == 2 => Some(EdnsOption::TcpKeepalive(Some(u16::from_be_bytes([
+                data[0], data[1],
+            ])))),
+            12 => Some(EdnsOption::Padding(data.len() as u16)),
+            15 if data.len() >= 2 => Some(EdnsOption::ExtendedError {
+                info_code: u16::from_be_bytes([data[0], data[1]]),
+                extra_text: String::from_utf8_lossy(&data[2..]).into_owned(),
+            }),
+            _ => None,
+        };
+
+        decoded.unwrap_or_else(|| EdnsOption::Unknown {
+            code,
+            data: data.to_vec(),
+        })
+    }
+
+    /// The data of the option, without the code and length
+    pub fn to_wire(&self) -> Vec<u8> {
+        match self {
+            EdnsOption::Nsid(data) => data.clone(),
+            EdnsOption::ClientSubnet {
+                family,
+                source_prefix,
+                scope_prefix,
+                address,
+            } => {
+                let mut data = family.to_be_bytes().to_vec();
+                data.push(*source_prefix);
+                data.push(*scope_prefix);
+                data.extend_from_slice(address);
+                data
+            }
+            EdnsOption::Cookie { client, server } => [client.as_slice(), server].concat(),
+            EdnsOption::TcpKeepalive(timeout) => {
+                timeout.map(|t| t.to_be_bytes().to_vec()).unwrap_or_default()
+            }
+            EdnsOption::Padding(len) => vec![0; *len as usize],
+            EdnsOption::ExtendedError {
+                info_code,
+                extra_text,
+            } => [&info_code.to_be_bytes(), extra_text.as_bytes()].concat(),
+            EdnsOption::Unknown { data, .. } => data.clone(),
+        }
+    }
+
+    /// Read every option out of an OPT record's RDATA
+    pub fn list_from_buffer(
+        buf: &mut BytePacketBuffer,
+        end: usize,
+    ) -> Result<Vec<EdnsOption>, crate::WireError> {
+        let mut options = vec![];
+
+        while buf.pos() < end {
+            let code = buf.read_u16()?;
+            let len = buf.read_u16()?;
+            let data = buf.read_bytes(len as usize)?;
+
+            options.push(EdnsOption::from_wire(code, &data));
+        }
+
+        Ok(options)
+    }
+
+    /// Write a list of options as an OPT record's RDATA
+    pub fn list_to_buffer(options: &[EdnsOption], buf: &mut BytePacketBuffer) {
+        for option in options {
+            let data = option.to_wire();
+
+            buf.write_u16(option.code());
+            buf.write_u16(data.len() as u16);
+            buf.write_bytes(&data);
+        }
+    }
+}
+
+impl fmt::Display for EdnsOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EdnsOption::ExtendedError {
+                info_code,
+                extra_text,
+            } => write!(f, "EDE {} {:?}", info_code, extra_text),
+            EdnsOption::Padding(len) => write!(f, "PADDING {}", len),
+            other => {
+                write!(f, "OPTION{} ", other.code())?;
+                for byte in other.to_wire() {
+                    write!(f, "{:02x}", byte)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The EDNS(0) information carried by a message's OPT pseudo-record
+///
+/// The OPT record reuses the fixed record fields: CLASS holds the sender's
+/// UDP payload size and TTL holds the upper bits of the RCODE, the version
+/// and the flags. RFC 6891 section 6.1.3.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns {
+    pub payload_size: u16,
+    pub ext_rcode: u8,
+    pub version: u8,
+    pub flags: u16,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Edns {
+    pub fn new(payload_size: u16) -> Edns {
+        Edns {
+            payload_size,
+            ext_rcode: 0,
+            version: 0,
+            flags: 0,
+            options: vec![],
+        }
+    }
+
+    /// The EDNS section to put in our reply to a client that sent `query`
+    pub fn reply_to(query: &Edns) -> Edns {
+        let mut edns = Edns::new(EDNS_PAYLOAD_SIZE);
+        edns.set_dnssec_ok(query.dnssec_ok());
+
+        edns
+    }
+
+    pub fn dnssec_ok(&self) -> bool {
+        self.flags & DNSSEC_OK != 0
+    }
+
+    pub fn set_dnssec_ok(&mut self, dnssec_ok: bool) {
+        if dnssec_ok {
+            self.flags |= DNSSEC_OK;
+        } else {
+            self.flags &= !DNSSEC_OK;
+        }
+    }
+
+    /// The largest UDP response we'll send the sender; anything below 512 is
+    /// treated as 512, RFC 6891 section 6.2.5, and we go no higher than the
+    /// size we advertise ourselves so answers don't get fragmented
+    pub fn udp_size(&self) -> usize {
+        self.payload_size.clamp(512, EDNS_PAYLOAD_SIZE) as usize
+    }
+
+    /// Find the first option with the given code
+    pub fn option(&self, code: u16) -> Option<&EdnsOption> {
+        self.options.iter().find(|o| o.code() == code)
+    }
+
+    /// Pull the EDNS information out of an OPT record
+    pub fn from_resource(rr: &DNSResource) -> Option<Edns> {
+        let options = match &rr.rdata {
+            RData::OPT(options) => options.clone(),
+            _ => return None,
+        };
+
+        Some(Edns {
+            payload_size: rr.class.to_wire(),
+            ext_rcode: (rr.ttl >> 24) as u8,
+            version: (rr.ttl >> 16) as u8,
+            flags: rr.ttl as u16,
+            options,
+        })
+    }
+
+    /// Build the OPT record carrying this information
+    pub fn to_resource(&self) -> DNSResource {
+        DNSResource {
+            name: DomainName::root(),
+            class: RecordClass::from_wire(self.payload_size),
+            ttl: (self.ext_rcode as u32) << 24 | (self.version as u32) << 16 | self.flags as u32,
+            rdata: RData::OPT(self.options.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, DNSMessage, DNSQuery, Server, WireError};
+    use std::sync::Arc;
+
+    fn query(edns: Option<Edns>) -> DNSMessage {
+        let mut message = DNSMessage::new();
+        message.queries.push(DNSQuery {
+            qname: "example.com.".parse().unwrap(),
+            ..DNSQuery::shell()
+        });
+        message.edns = edns;
+
+        message
+    }
+
+    /// A message with the given records as its additional section
+    fn with_additional(records: Vec<DNSResource>) -> Vec<u8> {
+        let mut message = query(None);
+        message.arc = records;
+
+        message.to_wire()
+    }
+
+    #[test]
+    fn opt_round_trips() {
+        let mut edns = Edns::new(4096);
+        edns.ext_rcode = 0x12;
+        edns.set_dnssec_ok(true);
+        edns.options = vec![
+            EdnsOption::Nsid(b"ns1".to_vec()),
+            EdnsOption::Cookie {
+                client: vec![1; 8],
+                server: vec![2; 16],
+            },
+            EdnsOption::TcpKeepalive(Some(300)),
+            EdnsOption::ExtendedError {
+                info_code: EDE_STALE_ANSWER,
+                extra_text: "old".to_string(),
+            },
+            EdnsOption::Unknown {
+                code: 65001,
+                data: vec![0xde, 0xad],
+            },
+        ];
+
+        let bytes = query(Some(edns.clone())).to_wire();
+        let parsed = DNSMessage::from_wire(&bytes).unwrap();
+
+        assert_eq!(parsed.edns, Some(edns));
+        assert!(parsed.arc.is_empty());
+    }
+
+    #[test]
+    fn the_do_bit() {
+        let mut edns = Edns::new(1232);
+        assert!(!edns.dnssec_ok());
+
+        edns.flags = 0x0001;
+        edns.set_dnssec_ok(true);
+        assert!(edns.dnssec_ok());
+        assert_eq!(edns.to_resource().ttl, 0x8001);
+
+        // the reply only carries the DO bit over, not whatever else was set
+        assert_eq!(Edns::reply_to(&edns).flags, 0x8000);
+
+        edns.set_dnssec_ok(false);
+        assert!(!edns.dnssec_ok());
+        assert_eq!(edns.flags, 0x0001);
+    }
+
+    #[test]
+    fn udp_size_is_clamped() {
+        assert_eq!(Edns::new(0).udp_size(), 512);
+        assert_eq!(Edns::new(1232).udp_size(), 1232);
+        assert_eq!(Edns::new(4096).udp_size(), EDNS_PAYLOAD_SIZE as usize);
+    }
+
+    #[test]
+    fn answers_newer_versions_with_badvers() {
+        let mut edns = Edns::new(1232);
+        edns.version = 1;
+
+        let server = Arc::new(Server::new(&Config::default()));
+        let reply = server.handle(query(Some(edns)));
+        let reply = DNSMessage::from_wire(&reply.to_wire()).unwrap();
+
+        let edns = reply.edns.expect("BADVERS goes in the OPT record");
+        assert_eq!(edns.ext_rcode, BADVERS);
+        assert_eq!(edns.version, 0);
+        assert!(reply.ans.is_empty());
+    }
+
+    #[test]
+    fn rejects_a_second_opt() {
+        let opt = Edns::new(1232).to_resource();
+        let bytes = with_additional(vec![opt.clone(), opt]);
+
+        assert!(matches!(
+            DNSMessage::from_wire(&bytes),
+            Err(WireError::BadOpt { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_an_opt_not_owned_by_the_root() {
+        let mut opt = Edns::new(1232).to_resource();
+        opt.name = "example.com.".parse().unwrap();
+        let bytes = with_additional(vec![opt]);
+
+        assert!(matches!(
+            DNSMessage::from_wire(&bytes),
+            Err(WireError::BadOpt { .. })
+        ));
+    }
+
+    #[test]
+    fn decodes_options() {
+        assert_eq!(
+            EdnsOption::from_wire(8, &[0, 1, 24, 0, 192, 0, 2]),
+            EdnsOption::ClientSubnet {
+                family: 1,
+                source_prefix: 24,
+                scope_prefix: 0,
+                address: vec![192, 0, 2],
+            }
+        );
+        assert_eq!(
+            EdnsOption::from_wire(11, &[]),
+            EdnsOption::TcpKeepalive(None)
+        );
+        assert_eq!(EdnsOption::from_wire(12, &[0; 5]), EdnsOption::Padding(5));
+        assert_eq!(
+            EdnsOption::from_wire(10, &[1; 8]),
+            EdnsOption::Cookie {
+                client: vec![1; 8],
+                server: vec![],
+            }
+        );
+
+        // data that doesn't fit the layout is kept as it came
+        for (code, data) in [(10, vec![1; 9]), (11, vec![1]), (15, vec![1])] {
+            assert_eq!(
+                EdnsOption::from_wire(code, &data),
+                EdnsOption::Unknown { code, data }
+            );
+        }
+    }
+
+    #[test]
+    fn option_lists_round_trip() {
+        let options = vec![
+            EdnsOption::Padding(3),
+            EdnsOption::TcpKeepalive(None),
+            EdnsOption::Nsid(vec![]),
+        ];
+
+        let mut buf = BytePacketBuffer::new();
+        EdnsOption::list_to_buffer(&options, &mut buf);
+        let end = buf.len();
+
+        let mut buf = BytePacketBuffer::from_bytes(buf.as_bytes());
+        assert_eq!(
+            EdnsOption::list_from_buffer(&mut buf, end).unwrap(),
+            options
+        );
+    }
+}
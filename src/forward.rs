@@ -0,0 +1,240 @@
+use std::{collections::HashMap, str::FromStr};
+
+use crate::{
+    config::Config,
+    name::DomainName,
+    resolvers::{ResolverSet, Strategy},
+};
+
+/// One `--forward` rule, before it is turned into a table entry
+///
+/// Written as `<zone>=refuse`, or `<zone>=<addr>[,<addr>...]` followed by
+/// any of `/<strategy>` and `/norecurse`, e.g.
+/// `corp.example=10.0.0.1:53,10.0.0.2:53/round-robin`.
+#[derive(Debug, Clone)]
+pub struct ForwardRule {
+    pub zone: DomainName,
+    pub target: ForwardTarget,
+}
+
+#[derive(Debug, Clone)]
+pub enum ForwardTarget {
+    /// Send the query to these resolvers, using the global strategy unless
+    /// one is given. Without `recurse` the query goes out with RD clear, for
+    /// forwarding straight to a zone's authoritative servers
+    Resolvers {
+        addrs: Vec<String>,
+        strategy: Option<Strategy>,
+        recurse: bool,
+    },
+    /// Don't look the name up at all, answer REFUSED
+    Refuse,
+}
+
+impl FromStr for ForwardRule {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (zone, target) = s.split_once('=').ok_or(())?;
+        let zone = zone.parse().map_err(|_| ())?;
+
+        if target == "refuse" {
+            return Ok(ForwardRule {
+                zone,
+                target: ForwardTarget::Refuse,
+            });
+        }
+
+        let mut parts = target.split('/');
+        let addrs = parts
+            .next()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        if addrs.iter().any(String::is_empty) {
+            return Err(());
+        }
+
+        let mut strategy = None;
+        let mut recurse = true;
+        for option in parts {
+            match option {
+                "norecurse" => recurse = false,
+                other => strategy = Some(other.parse().map_err(|_| ())?),
+            }
+        }
+
+        Ok(ForwardRule {
+            zone,
+            target: ForwardTarget::Resolvers {
+                addrs,
+                strategy,
+                recurse,
+            },
+        })
+    }
+}
+
+/// What to do with a query for a name under one zone
+#[derive(Debug)]
+pub enum ZoneAction {
+    Forward {
+        resolvers: ResolverSet,
+        recurse: bool,
+    },
+    /// Resolve the name ourselves, starting from the root
+    Recurse,
+    Refuse,
+}
+
+/// Where queries go, by the zone the name asked about falls under
+///
+/// The most specific zone wins, so a rule for `corp.example.` covers
+/// `www.corp.example.` but not when there is also one for
+/// `www.corp.example.` itself. The root zone is always present and sends
+/// everything else to the `--resolver` list, or in recursive mode has us
+/// resolve it ourselves.
+#[derive(Debug)]
+pub struct ForwardTable {
+    zones: HashMap<DomainName, ZoneAction>,
+}
+
+impl ForwardTable {
+    pub fn new(config: &Config) -> ForwardTable {
+        let mut zones = HashMap::new();
+
+        let default = if config.recursive {
+            ZoneAction::Recurse
+        } else {
+            ZoneAction::Forward {
+                resolvers: ResolverSet::new(config.resolvers.clone(), config.strategy),
+                recurse: true,
+            }
+        };
+        zones.insert(DomainName::root(), default);
+
+        // Later rules replace earlier ones for the same zone
+        for rule in &config.forwards {
+            let action = match &rule.target {
+                ForwardTarget::Resolvers {
+                    addrs,
+                    strategy,
+                    recurse,
+                } => ZoneAction::Forward {
+                    resolvers: ResolverSet::new(addrs.clone(), strategy.unwrap_or(config.strategy)),
+                    recurse: *recurse,
+                },
+                ForwardTarget::Refuse => ZoneAction::Refuse,
+            };
+
+            zones.insert(rule.zone.clone(), action);
+        }
+
+        ForwardTable { zones }
+    }
+
+    /// Find the rule for the closest zone enclosing `name`, along with that
+    /// zone's name
+    pub fn lookup(&self, name: &DomainName) -> (DomainName, &ZoneAction) {
+        let mut zone = name.clone();
+
+        loop {
+            if let Some(action) = self.zones.get(&zone) {
+                return (zone, action);
+            }
+
+            zone = zone.parent().expect("the root zone is always in the table");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> DomainName {
+        s.parse().unwrap()
+    }
+
+    fn resolvers(rule: &str) -> (Vec<String>, Option<Strategy>, bool) {
+        match rule.parse::<ForwardRule>().unwrap().target {
+            ForwardTarget::Resolvers {
+                addrs,
+                strategy,
+                recurse,
+            } => (addrs, strategy, recurse),
+            ForwardTarget::Refuse => panic!("{} refuses", rule),
+        }
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rule: ForwardRule = "corp.example=10.0.0.1:53,10.0.0.2:53".parse().unwrap();
+        assert_eq!(rule.zone, name("corp.example."));
+
+        assert_eq!(
+            resolvers("corp.example=10.0.0.1:53,10.0.0.2:53"),
+            (
+                vec!["10.0.0.1:53".to_string(), "10.0.0.2:53".to_string()],
+                None,
+                true
+            )
+        );
+        assert_eq!(
+            resolvers("corp.example=10.0.0.1:53/norecurse/round-robin"),
+            (
+                vec!["10.0.0.1:53".to_string()],
+                Some(Strategy::RoundRobin),
+                false
+            )
+        );
+
+        let rule: ForwardRule = "ads.example=refuse".parse().unwrap();
+        assert!(matches!(rule.target, ForwardTarget::Refuse));
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        for rule in [
+            "corp.example",
+            "corp.example=",
+            "corp.example=10.0.0.1:53,",
+            "corp.example=,10.0.0.1:53",
+            "corp.example=/norecurse",
+            "corp.example=10.0.0.1:53/quickest",
+            "corp..example=10.0.0.1:53",
+        ] {
+            assert!(rule.parse::<ForwardRule>().is_err(), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn the_closest_zone_wins() {
+        let config = Config {
+            forwards: ["corp.example=10.0.0.1:53", "www.corp.example=refuse"]
+                .iter()
+                .map(|rule| rule.parse().unwrap())
+                .collect(),
+            ..Config::default()
+        };
+        let table = ForwardTable::new(&config);
+
+        let (zone, action) = table.lookup(&name("mail.corp.example."));
+        assert_eq!(zone, name("corp.example."));
+        assert!(matches!(action, ZoneAction::Forward { resolvers, .. }
+            if resolvers.addrs() == ["10.0.0.1:53"]));
+
+        let (zone, action) = table.lookup(&name("a.www.corp.example."));
+        assert_eq!(zone, name("www.corp.example."));
+        assert!(matches!(action, ZoneAction::Refuse));
+
+        // a zone that only shares a suffix as text doesn't count
+        let (zone, _) = table.lookup(&name("notcorp.example."));
+        assert!(zone.is_root());
+
+        let (zone, action) = table.lookup(&DomainName::root());
+        assert!(zone.is_root());
+        assert!(matches!(action, ZoneAction::Forward { recurse: true, .. }));
+    }
+}
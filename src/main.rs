@@ -2,21 +2,153 @@
 #![allow(unused_imports)]
 #![allow(unused_variables)]
 #![allow(unused_assignments)]
+#![allow(clippy::upper_case_acronyms)]
+
+mod authority;
+mod bailiwick;
+mod cache;
+mod config;
+mod edns;
+mod forward;
+mod name;
+mod pool;
+mod rdata;
+mod recursor;
+mod resolvers;
+mod tcp;
+mod upstream;
+mod zonefile;
+
+use authority::Authority;
+use cache::{Answer, Cache};
+use config::Config;
+use edns::{Edns, EdnsOption, BADVERS, EDE_STALE_ANSWER, EDNS_PAYLOAD_SIZE};
+use forward::{ForwardTable, ZoneAction};
+use name::{DomainName, MAX_NAME_LEN};
+use pool::ThreadPool;
+use rdata::{RData, RecordClass, RecordType};
+use recursor::{Recursor, ResolveError, MAX_CNAMES};
+use upstream::Upstream;
+
+use std::{
+    collections::{vec_deque, HashMap},
+    env::args,
+    fmt,
+    fs::File,
+    io::Read,
+    net::{TcpListener, UdpSocket},
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+/// The part of a message a decoder was working on, reported with errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Section {
+    #[default]
+    Header,
+    Question,
+    Answer,
+    Authority,
+    Additional,
+}
 
-use std::{collections::vec_deque, env::args, fs::File, io::Read, net::UdpSocket};
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Section::Header => "header",
+            Section::Question => "question section",
+            Section::Answer => "answer section",
+            Section::Authority => "authority section",
+            Section::Additional => "additional section",
+        };
 
-#[derive(Debug)]
-struct BytePacketBufffer {
-    buf: [u8; 512],
+        f.write_str(name)
+    }
+}
+
+/// Everything that can go wrong while decoding a message off the wire
+///
+/// Each variant records the byte offset the problem was found at and the
+/// section that was being parsed.
+#[derive(Debug, thiserror::Error)]
+enum WireError {
+    #[error("message truncated at offset {offset} in {section}")]
+    Truncated { offset: usize, section: Section },
+    #[error("invalid label type {label:#04x} at offset {offset} in {section}")]
+    BadLabel {
+        offset: usize,
+        label: u8,
+        section: Section,
+    },
+    #[error("compression pointer loop at offset {offset} in {section}")]
+    PointerLoop { offset: usize, section: Section },
+    #[error("name longer than 255 bytes at offset {offset} in {section}")]
+    NameTooLong { offset: usize, section: Section },
+    #[error("rdlength {rdlength} overruns the message at offset {offset} in {section}")]
+    RdataOverrun {
+        offset: usize,
+        rdlength: u16,
+        section: Section,
+    },
+    #[error("unexpected OPT record at offset {offset} in {section}")]
+    BadOpt { offset: usize, section: Section },
+    #[error("malformed rdata for type {rtype} at offset {offset} in {section}")]
+    BadRdata {
+        offset: usize,
+        rtype: u16,
+        section: Section,
+    },
+}
+
+/// Reader and writer for DNS messages in wire format
+///
+/// Backed by a growable buffer so messages are not limited to 512 bytes.
+/// Every read is bounds-checked and returns an error instead of panicking on
+/// truncated input. Writes always append to the end of the buffer, and names
+/// written through `write_qname` are compressed against the names already in
+/// the buffer.
+#[derive(Debug, Default)]
+struct BytePacketBuffer {
+    buf: Vec<u8>,
     pos: usize,
+    section: Section,
+    /// Offsets of every name suffix written so far, keyed by the suffix in
+    /// wire format
+    names: HashMap<Vec<u8>, usize>,
 }
 
-impl BytePacketBufffer {
-    /// Gives us a fresh buffer to hold the message, and a pointer into the buff
-    fn new() -> BytePacketBufffer {
-        BytePacketBufffer {
-            buf: [0; 512],
+impl BytePacketBuffer {
+    /// Gives us a fresh, empty buffer to write a message into
+    fn new() -> BytePacketBuffer {
+        BytePacketBuffer {
+            buf: Vec::with_capacity(512),
+            pos: 0,
+            section: Section::Header,
+            names: HashMap::new(),
+        }
+    }
+
+    /// Wrap received bytes so a message can be read out of them
+    fn from_bytes(data: &[u8]) -> BytePacketBuffer {
+        BytePacketBuffer {
+            buf: data.to_vec(),
             pos: 0,
+            section: Section::Header,
+            names: HashMap::new(),
+        }
+    }
+
+    /// Record which section is being parsed so errors can point at it
+    fn enter(&mut self, section: Section) {
+        self.section = section;
+    }
+
+    /// Build a truncation error for a read starting at `offset`
+    fn truncated(&self, offset: usize) -> WireError {
+        WireError::Truncated {
+            offset,
+            section: self.section,
         }
     }
 
@@ -25,233 +157,486 @@ impl BytePacketBufffer {
         self.pos
     }
 
-    /// Step pointer forward a specific number of bytes
-    fn step(&mut self, steps: usize) -> Result<(), ()> {
-        self.pos += steps;
+    /// Total number of bytes held in the buffer
+    fn len(&self) -> usize {
+        self.buf.len()
+    }
 
-        Ok(())
+    /// Bytes left between the pointer and the end of the buffer
+    fn remaining(&self) -> usize {
+        self.buf.len().saturating_sub(self.pos)
+    }
+
+    /// Step pointer forward a specific number of bytes
+    fn step(&mut self, steps: usize) -> Result<(), WireError> {
+        self.seek(self.pos + steps)
     }
 
     /// Move pointer to specific position
-    fn seek(&mut self, pos: usize) -> Result<(), ()> {
+    fn seek(&mut self, pos: usize) -> Result<(), WireError> {
+        if pos > self.buf.len() {
+            return Err(self.truncated(pos));
+        }
+
         self.pos = pos;
 
         Ok(())
     }
 
     /// Read one u8 and advance pointer
-    fn read(&mut self) -> Result<u8, ()> {
-        if self.pos >= 512 {
-            return Err(());
-        }
-
-        let res = self.buf[self.pos];
+    fn read(&mut self) -> Result<u8, WireError> {
+        let res = self.get(self.pos)?;
         self.pos += 1;
 
         Ok(res)
     }
 
     /// Get a single u8 without moving pointer
-    fn get(&mut self, pos: usize) -> Result<u8, ()> {
-        if pos >= 512 {
-            return Err(());
-        }
-
-        let res = self.buf[self.pos];
+    fn get(&self, pos: usize) -> Result<u8, WireError> {
+        self.buf.get(pos).copied().ok_or_else(|| self.truncated(pos))
+    }
 
-        Ok(res)
+    /// Get a range of bytes without moving pointer
+    fn get_range(&self, start: usize, len: usize) -> Result<&[u8], WireError> {
+        start
+            .checked_add(len)
+            .and_then(|end| self.buf.get(start..end))
+            .ok_or_else(|| self.truncated(start))
     }
 
-    /// Get a single u8 without moving pointer
-    fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8], ()> {
-        if start + len >= 512 {
-            return Err(());
-        }
+    /// Get a u16 without moving pointer
+    fn get_u16(&self, pos: usize) -> Result<u16, WireError> {
+        let bytes = self.get_range(pos, 2)?;
 
-        Ok(&self.buf[start..start + len])
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
     }
 
     /// Read one u16 and advance pointer
-    fn read_u16(&mut self) -> Result<u16, ()> {
+    fn read_u16(&mut self) -> Result<u16, WireError> {
         let res = ((self.read()? as u16) << 8) | (self.read()? as u16);
 
         Ok(res)
     }
 
     /// Read one u32 and advance pointer
-    fn read_u32(&mut self) -> Result<u32, ()> {
+    fn read_u32(&mut self) -> Result<u32, WireError> {
         let res = ((self.read_u16()? as u32) << 16) | (self.read_u16()? as u32);
 
         Ok(res)
     }
 
+    /// Read `len` bytes and advance pointer
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, WireError> {
+        let res = self.get_range(self.pos, len)?.to_vec();
+        self.pos += len;
+
+        Ok(res)
+    }
+
     /// Read a qname
     ///
     /// The tricky part: Reading domain names, taking labels into consideration.
-    /// Will take something like [3]www[6]google[3]com[0] and append
-    /// www.google.com to outstr.
-    fn read_qname(&mut self, outstr: &mut String) -> Result<(), ()> {
-        let pointer_location = self.pos();
-
-        // Track the number of segments
-        let _limit: usize = 64;
-        let mut segments = 0;
+    /// Will take something like [3]www[6]google[3]com[0], following any
+    /// compression pointers on the way, and return the complete name.
+    ///
+    /// Pointers may point backwards or forwards and may chain onto further
+    /// pointers. Every pointer target is remembered, so a chain which comes
+    /// back on itself is reported as a loop instead of spinning forever.
+    fn read_qname(&mut self) -> Result<DomainName, WireError> {
+        let start = self.pos();
+        let mut labels: Vec<Vec<u8>> = vec![];
+        let mut wire_len = 1;
 
-        // Track if we have jumped and how many times
-        let mut jumped: bool = false;
-        let _max_jumps = 10;
-        let mut jumps_done = 0;
+        // Offsets we have already jumped to while reading this name
+        let mut visited: Vec<usize> = vec![];
 
-        let mut delimiter = "";
+        // Where the name ends in the buffer; only the first pointer decides
+        // this, anything we jump to afterwards lives elsewhere in the message
+        let mut end: Option<usize> = None;
 
         let mut lpos = self.pos();
 
-        // Loop until we reach a null byte or we hit a segment/jump limit
+        // Loop until we reach the root label
         loop {
-            // We have to assume that the data is untrusted so we need to be
-            // paranoid. A message can be formed in which we keep jumping
-            // forever and consume CPU cycles
-            if jumps_done > _max_jumps {
-                return Err(());
-            }
-
             // Now we are at the beginning of a segment
             let segment_len = self.get(lpos)?;
 
-            // If the lenght has the most significant bits set then it must be
-            // a pointer
-            if (segment_len & 0xC0) == 0xC0 {
-                // Update the buffer position to a point past the jump. We don't
-                // need to touch it any further
-                if !jumped {
-                    self.seek(lpos + 2)?;
-                }
+            match segment_len & 0xC0 {
+                // If the two most significant bits are set then it must be a
+                // pointer to the rest of the name
+                0xC0 => {
+                    let b2 = self.get(lpos + 1)? as usize;
+                    let offset = ((segment_len & 0x3F) as usize) << 8 | b2;
 
-                // Read another byte, calcualte the offset and perform the jump
-                // by updating out local position variable
-                let b2 = self.get(lpos + 1)? as u16;
-                let offset = ((segment_len as u16) ^ 0xC0) << 8 | b2;
-                lpos = offset as usize;
+                    if end.is_none() {
+                        end = Some(lpos + 2);
+                    }
 
-                // indicate that a jumpt was performed
-                jumped = true;
-                jumps_done += 1;
+                    // We have to assume that the data is untrusted so we need
+                    // to be paranoid. A message can be formed in which we keep
+                    // jumping forever and consume CPU cycles
+                    if visited.contains(&offset) {
+                        return Err(WireError::PointerLoop {
+                            offset: lpos,
+                            section: self.section,
+                        });
+                    }
+                    visited.push(offset);
 
-                continue;
-            }
-            // The best scenario, reading a single label and appending it to output
-            else {
-                lpos += 1;
+                    lpos = offset;
+                }
+                // A plain label, at most 63 bytes long as the length only has
+                // six bits to work with
+                0x00 => {
+                    let label_len = segment_len as usize;
+
+                    // names are terminated by an empty label, so if the
+                    // length is zero we're done
+                    if label_len == 0 {
+                        lpos += 1;
+                        break;
+                    }
 
-                // names are terminated by an empty label, so if the length is
-                // zero we're done
-                if segment_len == 0 {
-                    break;
+                    // The whole name, length octets and root label included,
+                    // must fit in 255 bytes
+                    wire_len += 1 + label_len;
+                    if wire_len > MAX_NAME_LEN {
+                        return Err(WireError::NameTooLong {
+                            offset: lpos,
+                            section: self.section,
+                        });
+                    }
+
+                    labels.push(self.get_range(lpos + 1, label_len)?.to_vec());
+                    lpos += 1 + label_len;
                 }
+                // 0x40 and 0x80 are reserved label types we can't make sense of
+                _ => {
+                    return Err(WireError::BadLabel {
+                        offset: lpos,
+                        label: segment_len,
+                        section: self.section,
+                    });
+                }
+            }
+        }
+
+        self.seek(end.unwrap_or(lpos))?;
+
+        DomainName::from_labels(labels).map_err(|_| WireError::NameTooLong {
+            offset: start,
+            section: self.section,
+        })
+    }
+
+    /// Append one u8
+    fn write(&mut self, val: u8) {
+        self.buf.push(val);
+        self.pos = self.buf.len();
+    }
+
+    /// Append one u16
+    fn write_u16(&mut self, val: u16) {
+        self.write_bytes(&val.to_be_bytes());
+    }
+
+    /// Append one u32
+    fn write_u32(&mut self, val: u32) {
+        self.write_bytes(&val.to_be_bytes());
+    }
 
-                // append the delimiter to our output buffe first
-                outstr.push_str(delimiter);
+    /// Append a run of bytes
+    fn write_bytes(&mut self, val: &[u8]) {
+        self.buf.extend_from_slice(val);
+        self.pos = self.buf.len();
+    }
+
+    /// Overwrite a u16 that was already written, e.g. a count or a length
+    fn set_u16(&mut self, pos: usize, val: u16) -> Result<(), WireError> {
+        let section = self.section;
 
-                // Extract the actual ASCII bytes for this segment
-                let str_buffer = self.get_range(lpos, segment_len as usize);
+        self.buf
+            .get_mut(pos..pos + 2)
+            .ok_or(WireError::Truncated {
+                offset: pos,
+                section,
+            })?
+            .copy_from_slice(&val.to_be_bytes());
 
-                delimiter = ".";
+        Ok(())
+    }
 
-                lpos += segment_len as usize;
+    /// Append a name, compressing it
+    ///
+    /// Walks the name one label at a time. As soon as the remaining suffix is
+    /// one we have written before, a pointer to it is emitted and the name is
+    /// done. Otherwise the label is written out and the offset of the suffix
+    /// is remembered so later names can point at it.
+    fn write_qname(&mut self, name: &DomainName) {
+        let wire = name.to_wire();
+        let mut lpos = 0;
+
+        for label in name.labels() {
+            let suffix = &wire[lpos..];
+
+            if let Some(&offset) = self.names.get(suffix) {
+                self.write_u16(0xC000 | offset as u16);
+                return;
             }
-        }
 
-        if !jumped {
-            self.seek(lpos)?;
+            // Pointers only have 14 bits for the offset, anything written
+            // past that can't be pointed to
+            if self.buf.len() < 0x4000 {
+                self.names.insert(suffix.to_vec(), self.buf.len());
+            }
+
+            self.write_bytes(&wire[lpos..lpos + 1 + label.len()]);
+            lpos += 1 + label.len();
         }
 
-        Ok(())
+        self.write(0);
+    }
+
+    /// Append a name in full, for the places RFC 3597 forbids compression
+    fn write_qname_uncompressed(&mut self, name: &DomainName) {
+        self.write_bytes(&name.to_wire());
+    }
+
+    /// Throw away everything from `len` onwards, along with any compression
+    /// targets that pointed into it
+    fn truncate(&mut self, len: usize) {
+        self.buf.truncate(len);
+        self.pos = self.pos.min(len);
+        self.names.retain(|_, offset| *offset < len);
+    }
+
+    /// The bytes written so far
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Consume the buffer, handing back the underlying bytes
+    fn into_bytes(self) -> Vec<u8> {
+        self.buf
     }
 }
 
-#[allow(dead_code, unused_variables, unused_assignments)]
-#[derive(Debug)]
-struct DNSLabel<'a> {
-    parts: Vec<&'a str>,
+#[cfg(test)]
+mod buffer_tests {
+    use super::*;
+
+    fn name(s: &str) -> DomainName {
+        s.parse().unwrap()
+    }
+
+    /// Read a name starting at `pos`
+    fn read_at(bytes: &[u8], pos: usize) -> (Result<DomainName, WireError>, usize) {
+        let mut buf = BytePacketBuffer::from_bytes(bytes);
+        buf.seek(pos).unwrap();
+        let result = buf.read_qname();
+
+        (result, buf.pos())
+    }
+
+    #[test]
+    fn compressed_names_round_trip() {
+        let names = [
+            name("example.com."),
+            name("www.example.com."),
+            name("mail.example.com."),
+            name("example.org."),
+            name("."),
+        ];
+
+        let mut buf = BytePacketBuffer::new();
+        let mut offsets = vec![];
+        for name in &names {
+            offsets.push(buf.len());
+            buf.write_qname(name);
+        }
+
+        // www and mail only add their own label and a pointer
+        assert_eq!(offsets[2] - offsets[1], 1 + 3 + 2);
+        assert_eq!(offsets[3] - offsets[2], 1 + 4 + 2);
+
+        let mut buf = BytePacketBuffer::from_bytes(buf.as_bytes());
+        for expected in &names {
+            assert_eq!(&buf.read_qname().unwrap(), expected);
+        }
+        assert_eq!(buf.remaining(), 0);
+    }
+
+    #[test]
+    fn follows_chains_and_forward_pointers() {
+        let mut bytes = vec![0xC0, 0x04, 0xFF, 0xFF];
+        bytes.extend_from_slice(b"\x03com\x00");
+        bytes.extend_from_slice(b"\x07example\xC0\x04");
+        bytes.extend_from_slice(b"\x03www\xC0\x09");
+
+        // A pointer forward, the name ends right after it
+        let (read, pos) = read_at(&bytes, 0);
+        assert_eq!(read.unwrap(), name("com."));
+        assert_eq!(pos, 2);
+
+        // One pointer leading to another
+        let (read, pos) = read_at(&bytes, 19);
+        assert_eq!(read.unwrap(), name("www.example.com."));
+        assert_eq!(pos, bytes.len());
+    }
+
+    #[test]
+    fn rejects_pointer_loops() {
+        let (read, _) = read_at(&[0xC0, 0x00], 0);
+        assert!(matches!(read, Err(WireError::PointerLoop { .. })));
+
+        let (read, _) = read_at(&[0x01, b'a', 0xC0, 0x04, 0xC0, 0x00], 0);
+        assert!(matches!(read, Err(WireError::PointerLoop { .. })));
+    }
+
+    #[test]
+    fn rejects_malformed_names() {
+        // Four 63 byte labels come to 257 bytes with the root label
+        let mut long = vec![];
+        for _ in 0..4 {
+            long.push(63);
+            long.extend_from_slice(&[b'a'; 63]);
+        }
+        long.push(0);
+        let (read, _) = read_at(&long, 0);
+        assert!(matches!(read, Err(WireError::NameTooLong { .. })));
+
+        let (read, _) = read_at(&[0x40, 0x00], 0);
+        assert!(matches!(read, Err(WireError::BadLabel { label: 0x40, .. })));
+
+        let (read, _) = read_at(b"\x03ww", 0);
+        assert!(matches!(read, Err(WireError::Truncated { .. })));
+
+        let (read, _) = read_at(&[0xC0], 0);
+        assert!(matches!(read, Err(WireError::Truncated { .. })));
+    }
+
+    #[test]
+    fn never_points_past_the_offset_limit() {
+        let example = name("example.com.");
+
+        let mut buf = BytePacketBuffer::new();
+        buf.write_qname(&example);
+        buf.write_bytes(&[0; 0x4000]);
+
+        // Written early enough to point back at
+        let start = buf.len();
+        buf.write_qname(&example);
+        assert_eq!(buf.len() - start, 2);
+
+        // Written too late to be pointed at, so written out again in full
+        let other = name("example.org.");
+        buf.write_qname(&other);
+        let start = buf.len();
+        buf.write_qname(&other);
+        assert_eq!(buf.len() - start, other.wire_len());
+    }
+
+    #[test]
+    fn truncate_forgets_names_written_after() {
+        let mut buf = BytePacketBuffer::new();
+        buf.write_qname(&name("example.com."));
+        let len = buf.len();
+
+        buf.write_qname(&name("example.org."));
+        buf.truncate(len);
+
+        // Anything pointing at the discarded example.org would now land on
+        // the label below
+        buf.write_qname(&name("www.example.org."));
+        let (read, _) = read_at(buf.as_bytes(), len);
+        assert_eq!(read.unwrap(), name("www.example.org."));
+        assert_eq!(buf.len() - len, name("www.example.org.").wire_len());
+    }
 }
+
 #[derive(Debug, Clone)]
 struct DNSQuery {
-    qname: Vec<u8>,
-    qtype: u16,
-    qclass: u16,
+    qname: DomainName,
+    qtype: RecordType,
+    qclass: RecordClass,
 }
 
 #[derive(Debug, Clone)]
 struct DNSResource {
-    name: Vec<u8>,
-    rtype: u16,
-    class: u16,
+    name: DomainName,
+    class: RecordClass,
     ttl: u32,
-    rdlength: u16,
-    rdata: Vec<u8>,
+    rdata: RData,
 }
 
 impl DNSResource {
     fn shell() -> DNSResource {
         DNSResource {
-            name: vec![],
-            rtype: 0,
-            class: 0,
+            name: DomainName::root(),
+            class: RecordClass::IN,
             ttl: 0,
-            rdlength: 0,
-            rdata: vec![],
+            rdata: RData::Unknown {
+                rtype: 0,
+                bytes: vec![],
+            },
         }
     }
 
-    fn to_wire(&self) -> Vec<u8> {
-        let mut buf = vec![];
-
-        buf.extend_from_slice(&self.name);
-        buf.extend_from_slice(&self.rtype.to_be_bytes());
-        buf.extend_from_slice(&self.class.to_be_bytes());
-        buf.extend_from_slice(&self.ttl.to_be_bytes());
-        buf.extend_from_slice(&self.rdlength.to_be_bytes());
-        buf.extend_from_slice(&self.rdata);
-
-        buf
-    }
-
-    fn from_wire(buf: &mut RawWrapper) -> Option<Self> {
-        Some(DNSResource {
-            name: buf.name_from_wire(),
-            rtype: buf.get_u16(),
-            class: buf.get_u16(),
-            ttl: buf.get_u32(),
-            rdlength: buf.get_u16(),
-            rdata: {
-                buf.seek(buf.pos() - 2);
-                let length = buf.get_u16();
-
-                buf.take_from(buf.pos(), buf.pos() + length as usize)
-                    .to_vec()
-            },
-        })
+    /// The type of the record, which follows from its data
+    fn rtype(&self) -> RecordType {
+        self.rdata.rtype()
     }
 
-    fn from_buffer(&mut self, buf: &mut BytePacketBufffer) -> Result<(), ()> {
-        let mut s = String::new();
+    /// Write the record, filling in RDLENGTH once the data has been written
+    fn to_buffer(&self, buf: &mut BytePacketBuffer) {
+        buf.write_qname(&self.name);
+        buf.write_u16(self.rtype().to_wire());
+        buf.write_u16(self.class.to_wire());
+        buf.write_u32(self.ttl);
 
-        let res = buf.read_qname(&mut s)?;
+        let len_pos = buf.pos();
+        buf.write_u16(0);
+        self.rdata.to_buffer(buf);
 
-        self.name = s.as_bytes().to_vec();
-        self.rtype = buf.read_u16()?;
-        self.class = buf.read_u16()?;
-        self.ttl = buf.read_u32()?;
-        self.rdlength = buf.read_u16()?;
-        self.rdata = buf.get_range(buf.pos(), self.rdlength as usize)?.to_vec();
-        buf.step(self.rdlength as usize);
+        let rdlength = buf.pos() - len_pos - 2;
+        buf.set_u16(len_pos, rdlength as u16)
+            .expect("rdlength was just written");
+    }
 
-        Ok(())
+    fn from_buffer(buf: &mut BytePacketBuffer) -> Result<DNSResource, WireError> {
+        let name = buf.read_qname()?;
+        let rtype = RecordType::from_wire(buf.read_u16()?);
+        let class = RecordClass::from_wire(buf.read_u16()?);
+        let ttl = buf.read_u32()?;
+        let rdlength = buf.read_u16()?;
+        let rdata = RData::from_buffer(buf, rtype, rdlength)?;
+
+        Ok(DNSResource {
+            name,
+            class,
+            ttl,
+            rdata,
+        })
+    }
+}
+
+impl fmt::Display for DNSResource {
+    /// The record as a zone file line, unknown types in RFC 3597 form
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {}",
+            self.name,
+            self.ttl,
+            self.class,
+            self.rtype(),
+            self.rdata
+        )
     }
 }
 
 // ## Enums
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 enum OPCODE {
     QUERY,
     IQUERY,
@@ -259,7 +644,7 @@ enum OPCODE {
     RESERVED(u8),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 enum RCODE {
     NoErr,
     FormatErr,
@@ -318,187 +703,48 @@ impl RCODE {
     }
 }
 impl DNSQuery {
-    fn from_wire(buf: &mut RawWrapper) -> Option<DNSQuery> {
-        Some(DNSQuery {
-            qname: buf.name_from_wire(),
-            qtype: buf.get_u16(),
-            qclass: buf.get_u16(),
-        })
-    }
-
     fn shell() -> DNSQuery {
         DNSQuery {
-            qname: vec![],
-            qtype: 0,
-            qclass: 0,
+            qname: DomainName::root(),
+            qtype: RecordType::A,
+            qclass: RecordClass::IN,
         }
     }
 
-    fn from_buffer(&mut self, buf: &mut BytePacketBufffer) -> Result<(), ()> {
-        let mut s = String::new();
-
-        let res = buf.read_qname(&mut s);
-
-        self.qname = s.as_bytes().to_vec();
-
-        self.qtype = buf.read_u16()?;
-        self.qclass = buf.read_u16()?;
-
-        Ok(())
-    }
-
-    fn to_wire(&self) -> Vec<u8> {
-        let mut buf = vec![];
-
-        buf.extend_from_slice(&self.qname);
-        buf.extend_from_slice(&self.qtype.to_be_bytes());
-        buf.extend_from_slice(&self.qclass.to_be_bytes());
-
-        buf
-    }
-}
-
-#[derive(Debug)]
-struct RawWrapper {
-    data: Vec<u8>,
-    pos: usize,
-    consumed: usize,
-}
-
-impl RawWrapper {
-    fn new(buf: &[u8]) -> Self {
-        RawWrapper {
-            data: buf.to_vec(),
-            pos: 0,
-            consumed: 0,
-        }
-    }
-
-    fn seek(&mut self, pos: usize) -> Option<()> {
-        if pos > self.data.len() {
-            None
-        } else {
-            self.pos = pos;
-
-            Some(())
-        }
-    }
-
-    fn pos(&self) -> usize {
-        self.pos
-    }
-
-    fn get_u8(&mut self) -> u8 {
-        let byte = self.data[self.pos];
-        self.pos += 1;
-        self.consumed += 1;
-
-        byte
-    }
-
-    fn get_u16(&mut self) -> u16 {
-        u16::from_be_bytes([self.get_u8(), self.get_u8()])
-    }
-
-    fn get_u32(&mut self) -> u32 {
-        u32::from_be_bytes([self.get_u8(), self.get_u8(), self.get_u8(), self.get_u8()])
-    }
-
-    fn peek(&mut self) -> u8 {
-        self.data[self.pos() + 1]
-    }
-
-    fn name_from_wire(&mut self) -> Vec<u8> {
-        let mut dns_label = vec![];
-        let _limit: usize = 64;
-        let mut recursion = 0;
-
-        let reference = self.pos();
-        let mut marker = reference;
-        let mut in_label = true;
-        let mut followed_pointer = false;
-
-        let mut byte = self.get_u8();
-
-        loop {
-            if (((byte as u16) << 7 | self.peek() as u16) & 0xc000) == 0xC000 && !followed_pointer {
-                // this is a pointer
-                followed_pointer = true;
-                recursion += 1;
-
-                if in_label {
-                    in_label = false;
-                    match self.pos() - reference > 63 {
-                        true => dns_label.extend_from_slice(&self.data[reference..reference + 63]),
-                        false => dns_label.extend_from_slice(&self.data[reference..self.pos()]),
-                    }
-                }
-
-                let offset = self.get_u16() & 0x3FF;
-                if offset < marker as u16 {
-                    self.seek(offset as usize);
-                    marker = offset as usize;
-                    in_label = true;
-                } else {
-                    unimplemented!(
-                        "Have not implemented chance that reference offset is past start of string"
-                    )
-                }
-            } else if (((byte as u16) << 7 | self.peek() as u16) & 0xc000) == 0xC000 {
-                break;
-                // unimplemented!(
-                //     "Have not implemented logic in case we run into pointer after pointer"
-                // );
-            } else if byte == 0 {
-                if in_label {
-                    in_label = false;
-                    match self.pos() - reference > 63 {
-                        true => {
-                            dns_label.extend_from_slice(self.take_from(reference, reference + 63))
-                        }
-                        false => dns_label.extend_from_slice(self.take_from(reference, self.pos())),
-                    }
-                }
-                // dns_label.push(0);
-
-                break;
-            } else if in_label {
-                // todo this is a normal string
-                let length = byte as usize;
-                self.seek(self.pos() + length);
-                byte = self.get_u8();
-            }
-        }
-
-        // dbg!(&dns_label);
-        dns_label
+    fn from_buffer(buf: &mut BytePacketBuffer) -> Result<DNSQuery, WireError> {
+        Ok(DNSQuery {
+            qname: buf.read_qname()?,
+            qtype: RecordType::from_wire(buf.read_u16()?),
+            qclass: RecordClass::from_wire(buf.read_u16()?),
+        })
     }
 
-    fn take_from(&mut self, start: usize, end: usize) -> &[u8] {
-        let buf = &self.data[start..end];
-        self.consumed += end - start;
-        buf
+    fn to_buffer(&self, buf: &mut BytePacketBuffer) {
+        buf.write_qname(&self.qname);
+        buf.write_u16(self.qtype.to_wire());
+        buf.write_u16(self.qclass.to_wire());
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 enum Transport {
     TCP,
     UDP,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct DNSMessage {
     transport: Transport,
-    raw: RawWrapper,
     header: DNSHeader,
     queries: Vec<DNSQuery>,
     ans: Vec<DNSResource>,
     nsr: Vec<DNSResource>,
     arc: Vec<DNSResource>,
+    /// EDNS(0) information, from the OPT record in the additional section
+    edns: Option<Edns>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct DNSHeader {
     id: u16,
     qr: bool,
@@ -518,188 +764,285 @@ struct DNSHeader {
 }
 
 impl DNSMessage {
-    fn new(buf: &[u8]) -> Self {
+    /// Create an empty DNSMessage, used as a shell for initialization
+    fn new() -> DNSMessage {
         DNSMessage {
             transport: Transport::UDP,
-            raw: RawWrapper::new(buf),
             header: DNSHeader::shell(),
             queries: vec![],
             ans: vec![],
             nsr: vec![],
             arc: vec![],
+            edns: None,
         }
     }
 
-    fn from_wire(&mut self) {
-        // parse header information
-        self.header.from_wire(&mut self.raw);
-
-        // parse queries
-        let mut queries = vec![];
-        let mut answers = vec![];
-
-        for _ in 0..self.header.qdcount {
-            dbg!("Processing query...");
-            let a = DNSQuery::from_wire(&mut self.raw).unwrap();
-            queries.push(a);
-        }
-
-        for _ in 0..self.header.ancount {
-            let a = DNSResource::from_wire(&mut self.raw).unwrap();
-            answers.push(a);
-        }
-
-        self.queries = queries;
-        self.ans = answers;
-    }
-
-    /// Create an empty DNSMessage, used as a shell for initialization
-    fn shell() -> DNSMessage {
-        DNSMessage {
-            transport: Transport::UDP,
-            raw: RawWrapper::new(&vec![0]),
-            header: DNSHeader::shell(),
-            queries: vec![],
-            ans: vec![],
-            nsr: vec![],
-            arc: vec![],
-        }
+    /// Parse a DNS message out of the bytes received from the network
+    fn from_wire(data: &[u8]) -> Result<DNSMessage, WireError> {
+        DNSMessage::from_buffer(&mut BytePacketBuffer::from_bytes(data))
     }
 
     /// Parse a DNS message from an underlying buffer
-    fn from_buffer(buffer: &mut BytePacketBufffer) -> Result<DNSMessage, ()> {
-        let mut result = DNSMessage::shell();
+    fn from_buffer(buffer: &mut BytePacketBuffer) -> Result<DNSMessage, WireError> {
+        let mut result = DNSMessage::new();
 
         // extract header information
-        result.header.from_buffer(buffer)?;
+        buffer.enter(Section::Header);
+        result.header = DNSHeader::from_buffer(buffer)?;
 
         // extract queries
+        buffer.enter(Section::Question);
         for _ in 0..result.header.qdcount {
-            let mut queston = DNSQuery::shell();
-            queston.from_buffer(buffer)?;
-            result.queries.push(queston);
+            result.queries.push(DNSQuery::from_buffer(buffer)?);
         }
 
         // extract answers
+        buffer.enter(Section::Answer);
         for _ in 0..result.header.ancount {
-            let mut res = DNSResource::shell();
-            res.from_buffer(buffer)?;
-            result.ans.push(res);
+            result.ans.push(DNSResource::from_buffer(buffer)?);
         }
 
-        Ok(result)
-    }
+        // extract name server records, e.g. referrals and SOA for negative
+        // answers
+        buffer.enter(Section::Authority);
+        for _ in 0..result.header.nscount {
+            result.nsr.push(DNSResource::from_buffer(buffer)?);
+        }
 
-    fn process_queries(&mut self, socket: &mut UdpSocket, resolver: Option<&String>) {
-        let _queries = &self.queries;
+        // extract additional records, e.g. glue. The OPT pseudo-record lives
+        // here too, but it describes the message rather than any name so it
+        // is kept apart; a message may only carry one, owned by the root
+        buffer.enter(Section::Additional);
+        for _ in 0..result.header.arcount {
+            let offset = buffer.pos();
+            let rr = DNSResource::from_buffer(buffer)?;
 
-        if self.header.opcode != OPCODE::QUERY {
-            self.header.rcode = RCODE::NotImplemented
+            if rr.rtype() != RecordType::OPT {
+                result.arc.push(rr);
+                continue;
+            }
+
+            if result.edns.is_some() || !rr.name.is_root() {
+                return Err(WireError::BadOpt {
+                    offset,
+                    section: Section::Additional,
+                });
+            }
+            result.edns = Edns::from_resource(&rr);
         }
 
-        let mut answers = vec![];
-        dbg!(resolver);
+        Ok(result)
+    }
+
+    /// Turn a query into its answer from the cache
+    fn answer_from_cache(mut self, answer: Answer, edns: Option<Edns>) -> DNSMessage {
+        self.prepare_answer();
+        self.header.rcode = answer.rcode;
+        self.ans = answer.records;
+        self.nsr = answer.authority;
+        self.edns = edns;
 
-        match resolver {
-            Some(res) => {
-                let mut buf = [0; 512];
+        self
+    }
+
+    /// Fill in the answer to the question from the zones we serve, or
+    /// refuse it when the name isn't in any of them
+    fn answer_from_authority(&mut self, authority: &Authority) {
+        let Some(answer) = self.queries.first().and_then(|q| authority.answer(q)) else {
+            println!("Refusing query outside our zones");
+            self.header.rcode = RCODE::Refused;
+            return;
+        };
 
-                let mut answers = vec![];
+        self.header.aa = answer.authoritative;
+        self.header.rcode = answer.rcode;
+        self.ans = answer.answer;
+        self.nsr = answer.authority;
+        self.arc = answer.additional;
+    }
 
-                for _ in &self.queries {
-                    let mut shell = DNSMessage::new(&[]);
-                    shell.queries = vec![self.queries[0].clone()];
-                    shell.header.qdcount = 1;
-                    let q = shell.to_wire();
+    /// Turn the query into the shell of its response, keeping the ID, RD
+    /// and CD but none of the flags only an answer gets to set
+    fn prepare_answer(&mut self) {
+        self.header.qr = true;
+        // We don't validate, so can't vouch for anything being authentic
+        self.header.ad = false;
+        self.header.aa = false;
+        self.header.tc = false;
+        self.header.z = false;
+        self.header.rcode = match self.header.opcode {
+            OPCODE::QUERY => RCODE::NoErr,
+            _ => RCODE::NotImplemented,
+        }
+    }
 
-                    socket.send_to(&q, res).expect("Failed to query resolver");
-                    socket.recv_from(&mut buf).expect("Failed to read response");
+    /// Serialise the message into an underlying buffer
+    fn to_buffer(&self, buf: &mut BytePacketBuffer) {
+        self.to_buffer_limited(buf, usize::MAX);
+    }
 
-                    let mut response = DNSMessage::new(&buf);
+    /// Serialise the message, leaving out records that would take it past
+    /// `max_size` bytes
+    ///
+    /// Records are dropped from the first one that doesn't fit onwards, so the
+    /// message stays well-formed. Losing answer or authority records sets TC
+    /// so the client knows to retry over TCP; additional records are only a
+    /// courtesy and can go quietly, RFC 2181 section 9. The OPT record always
+    /// has room kept for it.
+    ///
+    /// The section counts in the header are taken from the records actually
+    /// written rather than from `self.header`, so the two can't disagree.
+    fn to_buffer_limited(&self, buf: &mut BytePacketBuffer, max_size: usize) {
+        let start = buf.pos();
+        self.header.to_buffer(buf);
 
-                    response.from_wire();
-                    dbg!(&response.header);
-                    dbg!(&response.queries);
-                    dbg!(&response.ans);
+        for q in &self.queries {
+            q.to_buffer(buf);
+        }
 
-                    answers.push(response.ans[0].clone());
+        let opt = self.edns.as_ref().map(Edns::to_resource);
+        let reserved = opt.as_ref().map_or(0, |rr| {
+            let mut scratch = BytePacketBuffer::new();
+            rr.to_buffer(&mut scratch);
+            scratch.len()
+        });
+        let limit = start.saturating_add(max_size.saturating_sub(reserved));
+
+        let mut counts = [self.queries.len(), 0, 0, 0];
+        let mut full = false;
+        let mut truncated = false;
+
+        let sections = [(&self.ans, true), (&self.nsr, true), (&self.arc, false)];
+        for (i, (records, required)) in sections.iter().enumerate() {
+            for rr in records.iter() {
+                if full {
+                    break;
                 }
 
-                self.ans = answers;
-                self.header.ancount = self.ans.len() as u16;
-            }
-            None => {
-                for a in &self.queries {
-                    answers.push(DNSResource {
-                        name: a.qname.clone(),
-                        rtype: 1,
-                        class: 1,
-                        ttl: 127389,
-                        rdlength: 4,
-                        rdata: b"\x08\x08\x08\x08".to_vec(),
-                    })
+                let before = buf.len();
+                rr.to_buffer(buf);
+
+                if buf.len() > limit {
+                    buf.truncate(before);
+                    full = true;
+                    truncated = *required;
+                    break;
                 }
 
-                self.header.ancount = answers.len() as u16;
-                self.ans = answers;
+                counts[i + 1] += 1;
             }
         }
 
-        self.prepare_answer();
-    }
+        if let Some(opt) = &opt {
+            opt.to_buffer(buf);
+            counts[3] += 1;
+        }
 
-    fn prepare_answer(&mut self) {
-        self.header.qr = true;
-        self.header.rcode = match self.header.opcode {
-            OPCODE::QUERY => RCODE::NoErr,
-            _ => RCODE::NotImplemented,
+        for (i, count) in counts.iter().enumerate() {
+            buf.set_u16(start + 4 + i * 2, *count as u16)
+                .expect("header was just written");
         }
-    }
 
-    fn process_que(&mut self) {
-        for q in self.queries.iter() {
-            let answer = DNSResource {
-                name: q.qname.clone(),
-                rtype: 1,
-                class: 1,
-                ttl: 50,
-                rdlength: 4,
-                rdata: b"\x08\x08\x08\x08".to_vec(),
-            };
-            self.ans.push(answer);
-            self.header.ancount += 1;
+        if truncated {
+            let flags = buf.get_u16(start + 2).expect("header was just written");
+            buf.set_u16(start + 2, flags | 0x200)
+                .expect("header was just written");
         }
     }
 
-    fn add_fake_answer(&mut self) {
-        let answer = DNSResource {
-            name: b"\x0ccodecrafters\x02io\x00".to_vec(),
-            rtype: 1,
-            class: 1,
-            ttl: 50,
-            rdlength: 4,
-            rdata: b"\x08\x08\x08\x08".to_vec(),
+    /// Largest UDP response the sender of this message will accept
+    fn max_udp_size(&self) -> usize {
+        self.edns.as_ref().map_or(512, Edns::udp_size)
+    }
+
+    /// Serialise the message into the bytes to put on the network
+    fn to_wire(&self) -> Vec<u8> {
+        let mut buf = BytePacketBuffer::new();
+        self.to_buffer(&mut buf);
+
+        buf.into_bytes()
+    }
+
+    /// Serialise the message into a response of at most `max_size` bytes,
+    /// setting TC if answers had to be left out
+    fn to_wire_limited(&self, max_size: usize) -> Vec<u8> {
+        let mut buf = BytePacketBuffer::new();
+        self.to_buffer_limited(&mut buf, max_size);
+
+        buf.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod message_tests {
+    use super::*;
+
+    /// A reply with `ans` answers and `arc` additional records of about 110
+    /// bytes each, and an OPT record
+    fn reply(ans: usize, arc: usize) -> DNSMessage {
+        let name: DomainName = "example.com.".parse().unwrap();
+        let record = DNSResource {
+            name: name.clone(),
+            ttl: 300,
+            rdata: RData::TXT(vec![vec![b'x'; 100]]),
+            ..DNSResource::shell()
         };
 
-        self.ans.push(answer);
-        self.header.ancount += 1;
+        let mut message = DNSMessage::new();
+        message.header.qr = true;
+        message.queries.push(DNSQuery {
+            qname: name,
+            qtype: RecordType::TXT,
+            ..DNSQuery::shell()
+        });
+        message.ans = vec![record.clone(); ans];
+        message.arc = vec![record; arc];
+        message.edns = Some(Edns::new(EDNS_PAYLOAD_SIZE));
+
+        message
     }
 
-    fn to_wire(&mut self) -> Vec<u8> {
-        let mut buf = vec![];
+    #[test]
+    fn dropping_answers_sets_tc() {
+        let bytes = reply(10, 0).to_wire_limited(512);
+        let parsed = DNSMessage::from_wire(&bytes).unwrap();
 
-        let header_wire = self.header.to_wire();
-        buf.extend_from_slice(&header_wire);
+        assert!(bytes.len() <= 512);
+        assert!(parsed.header.tc);
+        assert!(!parsed.ans.is_empty() && parsed.ans.len() < 10);
+    }
 
-        for q in &self.queries {
-            buf.extend_from_slice(&q.to_wire());
-        }
+    #[test]
+    fn dropping_additional_records_does_not() {
+        let bytes = reply(2, 10).to_wire_limited(512);
+        let parsed = DNSMessage::from_wire(&bytes).unwrap();
+
+        assert!(bytes.len() <= 512);
+        assert!(!parsed.header.tc);
+        assert_eq!(parsed.ans.len(), 2);
+        assert!(parsed.arc.len() < 10);
+    }
+
+    #[test]
+    fn counts_match_and_opt_always_fits() {
+        let message = reply(3, 3);
+        let full = message.to_wire().len();
 
-        for ans in &self.ans {
-            buf.extend_from_slice(&ans.to_wire());
+        for limit in 40..=full {
+            let bytes = message.to_wire_limited(limit);
+            assert!(bytes.len() <= limit, "{} bytes over {}", bytes.len(), limit);
+
+            // the counts cover exactly the records that were written
+            let mut buf = BytePacketBuffer::from_bytes(&bytes);
+            let parsed = DNSMessage::from_buffer(&mut buf).unwrap();
+            assert_eq!(buf.remaining(), 0);
+
+            assert!(parsed.edns.is_some());
+            assert_eq!(parsed.header.tc, parsed.ans.len() < 3);
         }
 
-        buf
+        let parsed = DNSMessage::from_wire(&message.to_wire_limited(full)).unwrap();
+        assert_eq!((parsed.ans.len(), parsed.arc.len()), (3, 3));
     }
 }
 
@@ -726,56 +1069,32 @@ impl DNSHeader {
 }
 
 impl DNSHeader {
-    fn from_wire(&mut self, wrapper: &mut RawWrapper) {
-        let id = wrapper.get_u16();
-
-        let flags = wrapper.get_u16();
-
-        self.id = id;
-        self.qr = flags >> 15 == 1;
-        self.opcode = OPCODE::from_wire(&flags);
-        self.aa = (flags & 0x400) >> 10 == 1;
-        self.tc = (flags & 0x200) >> 9 == 1;
-        self.rd = (flags & 0x100) >> 8 == 1;
-        self.ra = (flags & 0x80) >> 7 == 1;
-        self.z = (flags & 0x40) >> 6 == 1;
-        self.ad = (flags & 0x20) >> 5 == 1;
-        self.cd = (flags & 0x10) >> 4 == 1;
-        self.rcode = RCODE::from_wire(&flags);
-        self.qdcount = wrapper.get_u16();
-        self.ancount = wrapper.get_u16();
-        self.nscount = wrapper.get_u16();
-        self.arcount = wrapper.get_u16();
-    }
-
-    fn from_buffer(&mut self, buffer: &mut BytePacketBufffer) -> Result<(), ()> {
+    fn from_buffer(buffer: &mut BytePacketBuffer) -> Result<DNSHeader, WireError> {
         let id = buffer.read_u16()?;
 
         let flags = buffer.read_u16()?;
 
-        self.id = id;
-        self.qr = flags >> 15 == 1;
-        self.opcode = OPCODE::from_wire(&flags);
-        self.aa = (flags & 0x400) >> 10 == 1;
-        self.tc = (flags & 0x200) >> 9 == 1;
-        self.rd = (flags & 0x100) >> 8 == 1;
-        self.ra = (flags & 0x80) >> 7 == 1;
-        self.z = (flags & 0x40) >> 6 == 1;
-        self.ad = (flags & 0x20) >> 5 == 1;
-        self.cd = (flags & 0x10) >> 4 == 1;
-        self.rcode = RCODE::from_wire(&flags);
-        self.qdcount = buffer.read_u16()?;
-        self.ancount = buffer.read_u16()?;
-        self.nscount = buffer.read_u16()?;
-        self.arcount = buffer.read_u16()?;
-
-        Ok(())
+        Ok(DNSHeader {
+            id,
+            qr: flags >> 15 == 1,
+            opcode: OPCODE::from_wire(&flags),
+            aa: (flags & 0x400) >> 10 == 1,
+            tc: (flags & 0x200) >> 9 == 1,
+            rd: (flags & 0x100) >> 8 == 1,
+            ra: (flags & 0x80) >> 7 == 1,
+            z: (flags & 0x40) >> 6 == 1,
+            ad: (flags & 0x20) >> 5 == 1,
+            cd: (flags & 0x10) >> 4 == 1,
+            rcode: RCODE::from_wire(&flags),
+            qdcount: buffer.read_u16()?,
+            ancount: buffer.read_u16()?,
+            nscount: buffer.read_u16()?,
+            arcount: buffer.read_u16()?,
+        })
     }
 
-    fn to_wire(&self) -> Vec<u8> {
-        let mut buf = vec![];
-
-        buf.extend_from_slice(&self.id.to_be_bytes());
+    fn to_buffer(&self, buf: &mut BytePacketBuffer) {
+        buf.write_u16(self.id);
 
         let byte = ((self.qr as u16) << 15)
             | ((*self.opcode.to_wire() as u16) << 11)
@@ -788,107 +1107,579 @@ impl DNSHeader {
             | ((self.cd as u16) << 4)
             | (*self.rcode.to_wire() as u16);
 
-        buf.extend_from_slice(&byte.to_be_bytes());
-        buf.extend_from_slice(&self.qdcount.to_be_bytes());
-        buf.extend_from_slice(&self.ancount.to_be_bytes());
-        buf.extend_from_slice(&self.nscount.to_be_bytes());
-        buf.extend_from_slice(&self.arcount.to_be_bytes());
-
-        buf
+        buf.write_u16(byte);
+        buf.write_u16(self.qdcount);
+        buf.write_u16(self.ancount);
+        buf.write_u16(self.nscount);
+        buf.write_u16(self.arcount);
     }
 }
 
-fn main() {
-    // You can use print statements as follows for debugging, they'll be visible when running tests.
-    println!("Logs from your program will appear here!");
+/// Largest datagram we are prepared to receive from a client
+const RECV_BUFFER_SIZE: usize = 4096;
 
-    // Uncomment this block to pass the first stage
-    let udp_socket = UdpSocket::bind("127.0.0.1:2053").expect("Failed to bind to address");
-    let mut buf = [0; 512];
+/// Address we listen on for clients, over both UDP and TCP
+const LISTEN_ADDR: &str = "127.0.0.1:2053";
 
-    let args = args().collect::<Vec<String>>();
-    let mut res_socket = UdpSocket::bind("0.0.0.0:0").expect("Failed to bind to local");
+/// Number of queries that can be worked on at the same time
+const WORKERS: usize = 32;
 
-    let mut res_addr = "8.8.8.8:53".to_string();
+/// How long to wait on upstream before falling back on stale data, the
+/// client response timer of RFC 8767 section 5
+const STALE_ANSWER_DELAY: Duration = Duration::from_millis(1800);
 
-    for arg in std::env::args() {
-        if arg == "--resolver" {
-            res_addr = std::env::args().nth(2).clone().unwrap();
+/// Everything needed to answer a query, shared by the UDP and TCP listeners
+#[derive(Debug)]
+struct Server {
+    upstream: Upstream,
+    forwards: ForwardTable,
+    cache: Cache,
+    /// Only there in recursive mode
+    recursor: Option<Recursor>,
+    /// Only there when we serve zones of our own
+    authority: Option<Authority>,
+}
+
+impl Server {
+    fn new(config: &Config) -> Server {
+        let recursor = config.recursive.then(|| {
+            Recursor::new(config).unwrap_or_else(|e| {
+                eprintln!("Failed to load root hints {}: {}", config.root_hints, e);
+                std::process::exit(2);
+            })
+        });
+
+        let authority = (!config.zones.is_empty()).then(|| {
+            Authority::load(&config.zones).unwrap_or_else(|e| {
+                eprintln!("Failed to load zones: {}", e);
+                std::process::exit(2);
+            })
+        });
+
+        Server {
+            upstream: Upstream::new(config).expect("Failed to bind to local"),
+            forwards: ForwardTable::new(config),
+            cache: Cache::new(config),
+            recursor,
+            authority,
         }
     }
 
-    loop {
-        match udp_socket.recv_from(&mut buf) {
-            Ok((size, source)) => {
-                let _received_data = String::from_utf8_lossy(&buf[0..size]);
+    /// Work out the response to a client's query
+    fn handle(self: &Arc<Self>, mut ndns: DNSMessage) -> DNSMessage {
+        // The client's OPT record only describes its own query, our reply
+        // gets one of its own
+        let client_edns = ndns.edns.take();
 
-                println!("Received {} bytes from {}", size, source);
-                // dbg!(buf);
+        // Whatever the client sent, recursion is on offer unless we only
+        // serve our own zones
+        ndns.header.ra = self.authority.is_none();
+
+        if let Some(edns) = client_edns.as_ref().filter(|e| e.version > 0) {
+            println!("Unsupported EDNS version {}", edns.version);
+
+            let mut reply = Edns::new(EDNS_PAYLOAD_SIZE);
+            reply.ext_rcode = BADVERS;
+
+            ndns.prepare_answer();
+            ndns.edns = Some(reply);
+
+            return ndns;
+        }
+
+        // Only plain queries go any further, anything else would end up in
+        // the cache or be sent on upstream
+        if ndns.header.opcode != OPCODE::QUERY {
+            println!("Not implemented: opcode {:?}", ndns.header.opcode);
+
+            ndns.prepare_answer();
+            ndns.header.rcode = RCODE::NotImplemented;
+            ndns.edns = client_edns.as_ref().map(Edns::reply_to);
+
+            return ndns;
+        }
+
+        if let Some(authority) = &self.authority {
+            ndns.prepare_answer();
+            ndns.answer_from_authority(authority);
+            ndns.edns = client_edns.as_ref().map(Edns::reply_to);
+
+            return ndns;
+        }
+
+        let qname = ndns
+            .queries
+            .first()
+            .map(|q| q.qname.clone())
+            .unwrap_or_default();
+
+        if let (zone, ZoneAction::Refuse) = self.forwards.lookup(&qname) {
+            println!("Refusing query for {} under {}", qname, zone);
+
+            ndns.prepare_answer();
+            ndns.header.rcode = RCODE::Refused;
+            ndns.edns = client_edns.as_ref().map(Edns::reply_to);
+
+            return ndns;
+        }
+
+        let dnssec_ok = client_edns.as_ref().is_some_and(Edns::dnssec_ok);
+
+        let stale = match ndns.queries.as_slice() {
+            [query] => {
+                if let Some(answer) = self.cache.get(query, dnssec_ok) {
+                    println!("Answering {} from the cache", query.qname);
+
+                    let edns = client_edns.as_ref().map(Edns::reply_to);
+                    return ndns.answer_from_cache(answer, edns);
+                }
+
+                self.cache.get_stale(query, dnssec_ok)
+            }
+            _ => None,
+        };
+
+        let result = match stale {
+            None => self.resolve(&ndns.queries, dnssec_ok),
+            Some(stale) => {
+                match self.refresh(&ndns.queries[0], dnssec_ok) {
+                    Some(message) => Ok(message),
+                    None => {
+                        println!("Answering {} with stale data", qname);
+
+                        let mut edns = client_edns.as_ref().map(Edns::reply_to);
+                        if let Some(edns) = edns.as_mut() {
+                            edns.options.push(EdnsOption::ExtendedError {
+                                info_code: EDE_STALE_ANSWER,
+                                extra_text: String::new(),
+                            });
+                        }
+
+                        return ndns.answer_from_cache(stale, edns);
+                    }
+                }
+            }
+        };
+
+        let res_dns = match result {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("No resolver could answer: {}", e);
+
+                ndns.prepare_answer();
+                ndns.header.rcode = RCODE::ServerFail;
+                ndns.edns = client_edns.as_ref().map(Edns::reply_to);
+
+                return ndns;
+            }
+        };
+
+        ndns.ans = res_dns.ans;
+        ndns.nsr = res_dns.nsr;
+        ndns.arc = res_dns.arc;
+
+        ndns.prepare_answer();
+
+        // Pass on NXDOMAIN and friends, the SOA that came with them is in the
+        // authority section
+        ndns.header.rcode = res_dns.header.rcode;
 
-                // dbg!(buf.hex_dump());
-                let mut ndns = DNSMessage::new(&buf);
+        ndns.edns = client_edns.as_ref().map(Edns::reply_to);
 
-                let mut dns_buf = BytePacketBufffer::new();
-                dns_buf.buf = buf;
+        ndns
+    }
 
-                // let mut ndns = DNSMessage::from_buffer(&mut dns_buf).unwrap();
+    /// Find the answer to `queries`, by forwarding them or resolving them
+    /// ourselves depending on the zone, and cache it
+    fn resolve(&self, queries: &[DNSQuery], dnssec_ok: bool) -> Result<DNSMessage, ResolveError> {
+        self.resolve_at(queries, dnssec_ok, 0)
+    }
 
-                println!("Parse message");
-                ndns.from_wire();
-                // ndns.process_queries(&mut res_socket, res_addr.as_ref());
+    /// As `resolve`, `depth` CNAMEs into a chain that has left the zones it
+    /// started in
+    fn resolve_at(
+        &self,
+        queries: &[DNSQuery],
+        dnssec_ok: bool,
+        depth: usize,
+    ) -> Result<DNSMessage, ResolveError> {
+        let qname = queries.first().map(|q| q.qname.clone()).unwrap_or_default();
+        let (zone, action) = self.forwards.lookup(&qname);
 
-                println!("Start forward");
-                println!("Recursive Server is {:#?}", &res_addr);
-                // HERE IS WHERE WE NEED TO QUERY THE RECURSIVE RESOLVER
+        let res_dns = match (action, &self.recursor) {
+            (ZoneAction::Forward { resolvers, recurse }, _) => {
+                println!("Forwarding {} to {:?}", qname, resolvers.addrs());
 
-                println!("Creating forward packet");
-                let mut forward_buf = [0; 512];
-                let mut forward_dns = DNSMessage::new(&mut forward_buf);
+                let mut forward_dns = DNSMessage::new();
 
-                forward_dns.header.id = ndns.header.id;
                 forward_dns.header.qr = false;
                 forward_dns.header.opcode = OPCODE::QUERY;
-                forward_dns.queries = ndns.queries.clone();
-                forward_dns.header.qdcount = ndns.queries.len() as u16;
+                forward_dns.header.rd = *recurse;
+                forward_dns.queries = queries.to_vec();
+                forward_dns.header.qdcount = queries.len() as u16;
+
+                let mut forward_edns = Edns::new(EDNS_PAYLOAD_SIZE);
+                forward_edns.set_dnssec_ok(dnssec_ok);
+                forward_dns.edns = Some(forward_edns);
+
+                let mut res_dns = resolvers.exchange(&self.upstream, &forward_dns)?;
+                let cut = match queries {
+                    [query] => bailiwick::scrub(&mut res_dns, query, &zone),
+                    _ => None,
+                };
+
+                // The rest of the chain can only be trusted from whoever
+                // answers for the target, and what's left isn't an answer
+                // on its own
+                match (cut, queries) {
+                    (Some(target), [query]) if query.qtype != RecordType::CNAME => {
+                        self.follow_cname(query, target, res_dns, dnssec_ok, depth)?
+                    }
+                    _ => res_dns,
+                }
+            }
+            (ZoneAction::Recurse, Some(recursor)) if queries.len() == 1 => {
+                println!("Resolving {} from the root", qname);
+                recursor.resolve(&self.upstream, &queries[0], dnssec_ok)?
+            }
+            (action, _) => {
+                // Nothing to ask: a refused zone, or more than the one
+                // question we know how to resolve
+                let mut reply = DNSMessage::new();
+                reply.header.qr = true;
+                reply.header.rcode = match action {
+                    ZoneAction::Refuse => RCODE::Refused,
+                    _ => RCODE::FormatErr,
+                };
+                reply.queries = queries.to_vec();
+
+                reply
+            }
+        };
 
-                let raw_message = forward_dns.to_wire();
-                println!("Forward Message: {:#?}", &forward_dns);
-                println!("Raw Message: {:#?}", &raw_message);
+        println!(
+            "Got {:?} for {} with {} answers",
+            res_dns.header.rcode,
+            qname,
+            res_dns.ans.len()
+        );
+        for rr in &res_dns.ans {
+            println!("  {}", rr);
+        }
 
-                //// write to socket
-                println!("Sending message...");
-                res_socket
-                    .send_to(&raw_message, &res_addr)
-                    .expect("Failed to send data");
+        if let [query] = queries {
+            self.cache.insert(query, dnssec_ok, &res_dns);
+        }
 
-                let mut res_buffer = [0; 512];
-                println!("Waiting for message...");
-                res_socket
-                    .recv_from(&mut res_buffer)
-                    .expect("Failed to read data");
+        Ok(res_dns)
+    }
 
-                let mut res_dns = DNSMessage::new(&res_buffer);
-                res_dns.from_wire();
-                println!("Ans Message: {:#?}", &res_dns);
+    /// Finish off a CNAME chain in `partial` that was cut at `target` for
+    /// leaving the zone, by resolving the target on its own
+    fn follow_cname(
+        &self,
+        query: &DNSQuery,
+        target: DomainName,
+        mut partial: DNSMessage,
+        dnssec_ok: bool,
+        depth: usize,
+    ) -> Result<DNSMessage, ResolveError> {
+        if depth >= MAX_CNAMES {
+            return Err(ResolveError::TooManyCnames(query.qname.clone()));
+        }
 
-                ndns.ans = res_dns.ans;
-                // THE REST IS AS NORMAL
-                println!("Finished forward");
+        println!("Following CNAME from {} to {}", query.qname, target);
 
-                ndns.prepare_answer();
-                // ndns.add_fake_answer();
-                ndns.process_que();
+        let next = DNSQuery {
+            qname: target,
+            ..query.clone()
+        };
+        let rest = self.resolve_at(&[next], dnssec_ok, depth + 1)?;
+
+        partial.ans.extend(rest.ans);
+        partial.nsr = rest.nsr;
+        partial.arc = rest.arc;
+        partial.header.rcode = rest.header.rcode;
+
+        Ok(partial)
+    }
+
+    /// Look `query` up again in the background, waiting a short while for a
+    /// fresh answer before giving up on it, RFC 8767 section 5
+    ///
+    /// The lookup carries on after we stop waiting, so the cache is up to
+    /// date for the next client to ask. Only one refresh runs per question.
+    fn refresh(self: &Arc<Self>, query: &DNSQuery, dnssec_ok: bool) -> Option<DNSMessage> {
+        if !self.cache.start_refresh(query, dnssec_ok) {
+            println!("Already refreshing {}", query.qname);
+            return None;
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let refresh = Refresh {
+            server: Arc::clone(self),
+            queries: [query.clone()],
+            dnssec_ok,
+        };
+
+        thread::spawn(move || {
+            let result = refresh.server.resolve(&refresh.queries, dnssec_ok);
+            drop(refresh);
+
+            // Nobody may be waiting any more
+            let _ = sender.send(result);
+        });
+
+        match receiver.recv_timeout(STALE_ANSWER_DELAY) {
+            Ok(Ok(message))
+                if !matches!(message.header.rcode, RCODE::ServerFail | RCODE::Refused) =>
+            {
+                Some(message)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A background refresh of one question, which is marked as finished when
+/// this is dropped, so a lookup that panics doesn't block later refreshes
+struct Refresh {
+    server: Arc<Server>,
+    queries: [DNSQuery; 1],
+    dnssec_ok: bool,
+}
+
+impl Drop for Refresh {
+    fn drop(&mut self) {
+        self.server
+            .cache
+            .finish_refresh(&self.queries[0], self.dnssec_ok);
+    }
+}
+
+#[cfg(test)]
+mod server_tests {
+    use super::*;
+
+    /// A forwarding server whose only resolver never answers
+    fn server() -> Arc<Server> {
+        let config = Config {
+            resolvers: vec!["127.0.0.1:9".to_string()],
+            timeout: Duration::from_millis(50),
+            retries: 0,
+            ..Config::default()
+        };
+
+        Arc::new(Server::new(&config))
+    }
 
-                // dbg!(&ndns);
-                println!("{:#?}", ndns);
+    fn query(opcode: OPCODE) -> DNSMessage {
+        let mut message = DNSMessage::new();
+        message.header.opcode = opcode;
+        message.queries.push(DNSQuery {
+            qname: "example.com.".parse().unwrap(),
+            ..DNSQuery::shell()
+        });
 
-                let response = ndns.to_wire();
+        message
+    }
+
+    /// A query with every flag a client shouldn't be setting set
+    fn query_with_flags() -> DNSMessage {
+        let mut message = query(OPCODE::QUERY);
+        message.header.aa = true;
+        message.header.tc = true;
+        message.header.z = true;
+        message.header.ad = true;
+        message.header.rd = true;
+        message.header.cd = true;
+
+        message
+    }
+
+    fn assert_answer_flags(reply: &DNSMessage) {
+        let header = &reply.header;
+        assert!(header.qr && header.ra);
+        assert!(!header.aa && !header.tc && !header.z && !header.ad);
+        // RD and CD are the client's to set, and are copied back
+        assert!(header.rd && header.cd);
+    }
+
+    #[test]
+    fn failed_answers_only_copy_rd_and_cd() {
+        let reply = server().handle(query_with_flags());
+
+        assert_eq!(reply.header.rcode, RCODE::ServerFail);
+        assert_answer_flags(&reply);
+    }
+
+    #[test]
+    fn cached_answers_only_copy_rd_and_cd() {
+        let server = server();
+        let query = query_with_flags();
+
+        let mut upstream = DNSMessage::new();
+        upstream.header.qr = true;
+        upstream.header.aa = true;
+        upstream.header.rcode = RCODE::NoErr;
+        upstream.ans.push(DNSResource {
+            name: query.queries[0].qname.clone(),
+            ttl: 300,
+            rdata: RData::A([192, 0, 2, 1].into()),
+            ..DNSResource::shell()
+        });
+        server.cache.insert(&query.queries[0], false, &upstream);
+
+        let reply = server.handle(query);
+
+        assert_eq!(reply.ans.len(), 1);
+        assert_answer_flags(&reply);
+    }
+
+    #[test]
+    fn other_opcodes_are_not_implemented() {
+        let server = server();
+
+        for opcode in [OPCODE::IQUERY, OPCODE::STATUS, OPCODE::RESERVED(5)] {
+            let reply = server.handle(query(opcode));
+
+            assert!(reply.header.qr);
+            assert_eq!(reply.header.rcode, RCODE::NotImplemented);
+        }
+    }
+
+    /// Start a resolver that answers every query with `records`, returning
+    /// its address
+    fn stand_in(records: Vec<DNSResource>) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            let mut buf = [0; 512];
 
-                // dbg!(response.hex_dump());
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                let Ok(mut message) = DNSMessage::from_wire(&buf[..len]) else {
+                    continue;
+                };
+
+                message.edns = None;
+                message.prepare_answer();
+                message.ans = records.clone();
+
+                let _ = socket.send_to(&message.to_wire(), from);
+            }
+        });
+
+        addr
+    }
+
+    fn record(name: &str, rdata: RData) -> DNSResource {
+        DNSResource {
+            name: name.parse().unwrap(),
+            ttl: 300,
+            rdata,
+            ..DNSResource::shell()
+        }
+    }
 
-                udp_socket
-                    .send_to(&response, source)
-                    .expect("Failed to send response");
+    #[test]
+    fn follows_cnames_out_of_a_forwarded_zone() {
+        let corp = stand_in(vec![
+            record(
+                "www.corp.example.",
+                RData::CNAME("www.cdn.example.".parse().unwrap()),
+            ),
+            // not corp's to say
+            record("www.cdn.example.", RData::A([192, 0, 2, 66].into())),
+        ]);
+        let public = stand_in(vec![record(
+            "www.cdn.example.",
+            RData::A([192, 0, 2, 1].into()),
+        )]);
+
+        let config = Config {
+            resolvers: vec![public],
+            forwards: vec![format!("corp.example={}", corp).parse().unwrap()],
+            timeout: Duration::from_millis(500),
+            ..Config::default()
+        };
+        let server = Arc::new(Server::new(&config));
+
+        let mut query = query(OPCODE::QUERY);
+        query.queries[0].qname = "www.corp.example.".parse().unwrap();
+        let reply = server.handle(query.clone());
+
+        assert_eq!(reply.header.rcode, RCODE::NoErr);
+        assert_eq!(reply.ans.len(), 2);
+        assert_eq!(reply.ans[1].rdata, RData::A([192, 0, 2, 1].into()));
+
+        // and it's the whole chain that was cached
+        let cached = server.cache.get(&query.queries[0], false).unwrap();
+        assert_eq!(cached.records.len(), 2);
+    }
+}
+
+fn main() {
+    // You can use print statements as follows for debugging, they'll be visible when running tests.
+    println!("Logs from your program will appear here!");
+
+    // Uncomment this block to pass the first stage
+    let udp_socket = UdpSocket::bind(LISTEN_ADDR).expect("Failed to bind to address");
+    let tcp_listener = TcpListener::bind(LISTEN_ADDR).expect("Failed to bind to address");
+    let mut buf = [0; RECV_BUFFER_SIZE];
+
+    let config = match Config::from_args(args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let server = Arc::new(Server::new(&config));
+    let pool = Arc::new(ThreadPool::new(WORKERS));
+    let udp_socket = Arc::new(udp_socket);
+
+    {
+        let server = Arc::clone(&server);
+        let pool = Arc::clone(&pool);
+        thread::spawn(move || tcp::serve(tcp_listener, server, pool));
+    }
+
+    loop {
+        match udp_socket.recv_from(&mut buf) {
+            Ok((size, source)) => {
+                println!("Received {} bytes from {}", size, source);
+
+                let data = buf[..size].to_vec();
+                let server = Arc::clone(&server);
+                let udp_socket = Arc::clone(&udp_socket);
+
+                pool.execute(move || {
+                    let mut ndns = match DNSMessage::from_wire(&data) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            eprintln!("Failed to parse message from {}: {}", source, e);
+                            return;
+                        }
+                    };
+                    ndns.transport = Transport::UDP;
+
+                    let max_size = ndns.max_udp_size();
+
+                    let response = server.handle(ndns);
+                    println!(
+                        "Answering {} with {:?} and {} answers",
+                        source,
+                        response.header.rcode,
+                        response.ans.len()
+                    );
+
+                    if let Err(e) = udp_socket.send_to(&response.to_wire_limited(max_size), source)
+                    {
+                        eprintln!("Failed to send response to {}: {}", source, e);
+                    }
+                });
             }
             Err(e) => {
                 eprintln!("Error receiving data: {}", e);
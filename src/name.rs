@@ -0,0 +1,461 @@
+use std::{
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+
+/// Longest a single label may be, RFC 1035 section 2.3.4
+pub const MAX_LABEL_LEN: usize = 63;
+
+/// Longest a name may be in wire format, RFC 1035 section 2.3.4
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Reasons a name can be rejected when it is built or parsed
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum NameError {
+    #[error("label longer than 63 bytes")]
+    LabelTooLong,
+    #[error("name longer than 255 bytes")]
+    NameTooLong,
+    #[error("empty label")]
+    EmptyLabel,
+    #[error("invalid escape sequence")]
+    BadEscape,
+}
+
+/// A fully qualified domain name
+///
+/// Held as its labels, leftmost first, without the empty root label. Names
+/// compare and hash case-insensitively as RFC 4343 asks, while the original
+/// case is kept for display and for writing to the wire. `Ord` gives the
+/// canonical DNSSEC ordering from RFC 4034 section 6.1.
+#[derive(Clone, Default)]
+pub struct DomainName {
+    labels: Vec<Vec<u8>>,
+}
+
+impl DomainName {
+    /// The root name, `.`
+    pub fn root() -> DomainName {
+        DomainName { labels: vec![] }
+    }
+
+    /// Build a name out of its labels, leftmost first
+    pub fn from_labels(labels: Vec<Vec<u8>>) -> Result<DomainName, NameError> {
+        let mut wire_len = 1;
+
+        for label in &labels {
+            if label.is_empty() {
+                return Err(NameError::EmptyLabel);
+            }
+            if label.len() > MAX_LABEL_LEN {
+                return Err(NameError::LabelTooLong);
+            }
+
+            wire_len += 1 + label.len();
+        }
+
+        if wire_len > MAX_NAME_LEN {
+            return Err(NameError::NameTooLong);
+        }
+
+        Ok(DomainName { labels })
+    }
+
+    /// Parse a name as written in a zone file, where a name without a
+    /// trailing dot is relative to `origin` and `@` stands for `origin`
+    /// itself, RFC 1035 section 5.1
+    pub fn from_relative(s: &str, origin: &DomainName) -> Result<DomainName, NameError> {
+        if s == "@" {
+            return Ok(origin.clone());
+        }
+
+        let name = s.parse::<DomainName>()?;
+
+        // The dot at the end only makes the name absolute if it isn't
+        // escaped itself
+        let escapes = s.bytes().rev().skip(1).take_while(|&b| b == b'\\').count();
+        if s.ends_with('.') && escapes & 1 == 0 {
+            return Ok(name);
+        }
+
+        DomainName::from_labels(
+            name.labels
+                .into_iter()
+                .chain(origin.labels.iter().cloned())
+                .collect(),
+        )
+    }
+
+    /// Iterate over the labels, leftmost first
+    pub fn labels(&self) -> impl DoubleEndedIterator<Item = &[u8]> + ExactSizeIterator {
+        self.labels.iter().map(|l| l.as_slice())
+    }
+
+    /// Number of labels, not counting the root
+    pub fn label_count(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Length of the uncompressed name on the wire
+    pub fn wire_len(&self) -> usize {
+        self.labels.iter().map(|l| l.len() + 1).sum::<usize>() + 1
+    }
+
+    /// The uncompressed name in wire format, root label included
+    pub fn to_wire(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.wire_len());
+
+        for label in &self.labels {
+            buf.push(label.len() as u8);
+            buf.extend_from_slice(label);
+        }
+        buf.push(0);
+
+        buf
+    }
+
+    /// The name with its leftmost label removed, `None` for the root
+    pub fn parent(&self) -> Option<DomainName> {
+        if self.is_root() {
+            return None;
+        }
+
+        Some(DomainName {
+            labels: self.labels[1..].to_vec(),
+        })
+    }
+
+    /// The name made of the last `count` labels of this one, the whole name
+    /// if it has no more than that
+    pub fn ancestor(&self, count: usize) -> DomainName {
+        let skip = self.labels.len().saturating_sub(count);
+
+        DomainName {
+            labels: self.labels[skip..].to_vec(),
+        }
+    }
+
+    /// A new name with `label` added in front of this one
+    pub fn child(&self, label: &[u8]) -> Result<DomainName, NameError> {
+        let mut labels = Vec::with_capacity(self.labels.len() + 1);
+        labels.push(label.to_vec());
+        labels.extend(self.labels.iter().cloned());
+
+        DomainName::from_labels(labels)
+    }
+
+    /// True if this name is `other` or sits anywhere below it
+    pub fn is_subdomain_of(&self, other: &DomainName) -> bool {
+        self.labels.len() >= other.labels.len()
+            && self
+                .labels
+                .iter()
+                .rev()
+                .zip(other.labels.iter().rev())
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+
+    /// True if both names are the same down to the case of every letter
+    pub fn eq_exact(&self, other: &DomainName) -> bool {
+        self.labels == other.labels
+    }
+
+    /// The same name with the case of each letter flipped at random, for
+    /// the 0x20 trick from draft-vixie-dnsext-dns0x20
+    pub fn with_random_case(&self) -> DomainName {
+        DomainName {
+            labels: self
+                .labels
+                .iter()
+                .map(|l| {
+                    l.iter()
+                        .map(|b| match rand::random::<bool>() {
+                            true => b.to_ascii_uppercase(),
+                            false => b.to_ascii_lowercase(),
+                        })
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+}
+
+impl PartialEq for DomainName {
+    fn eq(&self, other: &Self) -> bool {
+        self.labels.len() == other.labels.len() && self.is_subdomain_of(other)
+    }
+}
+
+impl Eq for DomainName {}
+
+impl Hash for DomainName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.labels.len());
+
+        for label in &self.labels {
+            state.write_u8(label.len() as u8);
+            for byte in label {
+                state.write_u8(byte.to_ascii_lowercase());
+            }
+        }
+    }
+}
+
+impl Ord for DomainName {
+    /// Canonical ordering: compare labels from the right, each one as a
+    /// lower-cased octet string, with the shorter name first on a tie
+    fn cmp(&self, other: &Self) -> Ordering {
+        for (a, b) in self.labels.iter().rev().zip(other.labels.iter().rev()) {
+            let a = a.iter().map(u8::to_ascii_lowercase);
+            let b = b.iter().map(u8::to_ascii_lowercase);
+
+            match a.cmp(b) {
+                Ordering::Equal => continue,
+                ord => return ord,
+            }
+        }
+
+        self.labels.len().cmp(&other.labels.len())
+    }
+}
+
+impl PartialOrd for DomainName {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl FromStr for DomainName {
+    type Err = NameError;
+
+    /// Parse a name in presentation format, e.g. `www.example.com.`
+    ///
+    /// The trailing dot is optional, the name is always taken as absolute.
+    /// `\.` and `\\` style escapes and `\DDD` decimal escapes are understood.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "." {
+            return Ok(DomainName::root());
+        }
+
+        let mut labels = vec![];
+        let mut label = vec![];
+        let mut bytes = s.bytes();
+        let mut trailing_dot = false;
+
+        while let Some(byte) = bytes.next() {
+            trailing_dot = false;
+
+            match byte {
+                b'.' => {
+                    if label.is_empty() {
+                        return Err(NameError::EmptyLabel);
+                    }
+                    labels.push(std::mem::take(&mut label));
+                    trailing_dot = true;
+                }
+                b'\\' => {
+                    let next = bytes.next().ok_or(NameError::BadEscape)?;
+
+                    if next.is_ascii_digit() {
+                        let d2 = bytes.next().ok_or(NameError::BadEscape)?;
+                        let d3 = bytes.next().ok_or(NameError::BadEscape)?;
+
+                        if !d2.is_ascii_digit() || !d3.is_ascii_digit() {
+                            return Err(NameError::BadEscape);
+                        }
+
+                        let value = (next - b'0') as u16 * 100
+                            + (d2 - b'0') as u16 * 10
+                            + (d3 - b'0') as u16;
+
+                        label.push(u8::try_from(value).map_err(|_| NameError::BadEscape)?);
+                    } else {
+                        label.push(next);
+                    }
+                }
+                _ => label.push(byte),
+            }
+        }
+
+        if !label.is_empty() {
+            labels.push(label);
+        } else if !trailing_dot {
+            return Err(NameError::EmptyLabel);
+        }
+
+        DomainName::from_labels(labels)
+    }
+}
+
+impl fmt::Debug for DomainName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DomainName({})", self)
+    }
+}
+
+impl fmt::Display for DomainName {
+    /// Write the name in presentation format, always with the trailing dot
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_root() {
+            return f.write_str(".");
+        }
+
+        for label in &self.labels {
+            for &byte in label {
+                match byte {
+                    b'.' | b'\\' | b'"' | b'(' | b')' | b';' | b'@' | b'$' => {
+                        write!(f, "\\{}", byte as char)?
+                    }
+                    0x21..=0x7E => write!(f, "{}", byte as char)?,
+                    _ => write!(f, "\\{:03}", byte)?,
+                }
+            }
+            f.write_str(".")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+
+    use super::*;
+
+    fn name(s: &str) -> DomainName {
+        s.parse().unwrap()
+    }
+
+    fn hash(name: &DomainName) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn case_is_ignored_but_kept() {
+        let upper = name("WWW.Example.COM.");
+        let lower = name("www.example.com");
+
+        assert_eq!(upper, lower);
+        assert_eq!(hash(&upper), hash(&lower));
+        assert!(!upper.eq_exact(&lower));
+        assert_eq!(upper.to_string(), "WWW.Example.COM.");
+
+        assert_ne!(name("www.example.com."), name("www.example.org."));
+        assert_ne!(name("www.example.com."), name("example.com."));
+    }
+
+    #[test]
+    fn parses_escapes() {
+        let dotted = name(r"a\.b.example.");
+        assert_eq!(dotted.label_count(), 2);
+        assert_eq!(dotted.labels().next(), Some(&b"a.b"[..]));
+        assert_eq!(dotted.to_string(), r"a\.b.example.");
+
+        let decimal = name(r"\001\255z.example.");
+        assert_eq!(decimal.labels().next(), Some(&b"\x01\xffz"[..]));
+        assert_eq!(decimal.to_string(), r"\001\255z.example.");
+
+        assert_eq!(
+            r"\256.example.".parse::<DomainName>(),
+            Err(NameError::BadEscape)
+        );
+        assert_eq!(
+            r"\2x5.example.".parse::<DomainName>(),
+            Err(NameError::BadEscape)
+        );
+        assert_eq!(r"example\".parse::<DomainName>(), Err(NameError::BadEscape));
+        assert_eq!(
+            "a..example.".parse::<DomainName>(),
+            Err(NameError::EmptyLabel)
+        );
+    }
+
+    #[test]
+    fn enforces_length_limits() {
+        let label = "a".repeat(MAX_LABEL_LEN);
+        assert!(format!("{}.example.", label).parse::<DomainName>().is_ok());
+        assert_eq!(
+            format!("a{}.example.", label).parse::<DomainName>(),
+            Err(NameError::LabelTooLong)
+        );
+
+        // Four labels of 62 come to 253 bytes on the wire with the root
+        // label, one more byte of label still fits and two don't
+        let long = vec!["a".repeat(62); 4].join(".");
+        assert_eq!(name(&long).wire_len(), 253);
+        assert_eq!(name(&format!("a.{}", long)).wire_len(), 255);
+        assert_eq!(
+            format!("aa.{}", long).parse::<DomainName>(),
+            Err(NameError::NameTooLong)
+        );
+    }
+
+    #[test]
+    fn relative_names_take_the_origin() {
+        let origin = name("example.com.");
+
+        assert_eq!(DomainName::from_relative("@", &origin).unwrap(), origin);
+        assert_eq!(
+            DomainName::from_relative("www", &origin).unwrap(),
+            name("www.example.com.")
+        );
+        assert_eq!(
+            DomainName::from_relative("www.example.org.", &origin).unwrap(),
+            name("www.example.org.")
+        );
+
+        // An escaped dot is part of the label, so the name is still relative
+        let escaped = DomainName::from_relative(r"www\.", &origin).unwrap();
+        assert_eq!(escaped, name(r"www\..example.com."));
+
+        // An escaped backslash leaves the dot after it unescaped
+        let absolute = DomainName::from_relative(r"www\\.", &origin).unwrap();
+        assert_eq!(absolute, name(r"www\\."));
+    }
+
+    #[test]
+    fn orders_canonically() {
+        // RFC 4034 section 6.1
+        let sorted = [
+            "example.",
+            "a.example.",
+            "yljkjljk.a.example.",
+            "Z.a.example.",
+            r"zABC.a.EXAMPLE.",
+            "z.example.",
+            r"\001.z.example.",
+            "*.z.example.",
+            r"\200.z.example.",
+        ]
+        .map(name);
+
+        let mut shuffled = sorted.clone();
+        shuffled.reverse();
+        shuffled.swap(1, 6);
+        shuffled.sort();
+
+        for (got, want) in shuffled.iter().zip(&sorted) {
+            assert!(got.eq_exact(want), "{} != {}", got, want);
+        }
+    }
+
+    #[test]
+    fn subdomains() {
+        let example = name("example.com.");
+
+        assert!(name("www.Example.com.").is_subdomain_of(&example));
+        assert!(example.is_subdomain_of(&example));
+        assert!(example.is_subdomain_of(&DomainName::root()));
+        assert!(!example.is_subdomain_of(&name("www.example.com.")));
+        assert!(!name("badexample.com.").is_subdomain_of(&example));
+    }
+}
@@ -0,0 +1,52 @@
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed set of worker threads that run queued jobs
+///
+/// Lets many queries be worked on at once, each one free to block on the
+/// network, without spawning a thread for every datagram that arrives.
+#[derive(Debug)]
+pub struct ThreadPool {
+    sender: Mutex<mpsc::Sender<Job>>,
+}
+
+impl ThreadPool {
+    pub fn new(size: usize) -> ThreadPool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..size {
+            let receiver = Arc::clone(&receiver);
+
+            thread::spawn(move || loop {
+                let job = receiver.lock().expect("job queue lock poisoned").recv();
+
+                match job {
+                    Ok(job) => job(),
+                    // The pool has been dropped, nothing more will arrive
+                    Err(_) => break,
+                }
+            });
+        }
+
+        ThreadPool {
+            sender: Mutex::new(sender),
+        }
+    }
+
+    /// Queue a job for the next free worker
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .lock()
+            .expect("job queue lock poisoned")
+            .send(Box::new(job))
+            .expect("worker threads have gone away");
+    }
+}
@@ -0,0 +1,819 @@
+use std::{
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use crate::{edns::EdnsOption, name::DomainName, BytePacketBuffer, WireError};
+
+/// Reasons record types, classes or data can't be read from text
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum PresentationError {
+    #[error("unknown record type {0}")]
+    UnknownType(String),
+    #[error("unknown record class {0}")]
+    UnknownClass(String),
+    #[error("generic rdata must start with \\#")]
+    NotGeneric,
+    #[error("invalid length in generic rdata")]
+    BadLength,
+    #[error("invalid hex in generic rdata")]
+    BadHex,
+    #[error("generic rdata is {expected} bytes long but {found} were given")]
+    LengthMismatch { expected: usize, found: usize },
+    #[error("generic rdata is not valid for type {0}")]
+    BadData(RecordType),
+    #[error("wrong number of fields for type {0}")]
+    FieldCount(RecordType),
+    #[error("invalid field {text:?} for type {rtype}")]
+    BadField { rtype: RecordType, text: String },
+}
+
+/// The TYPE of a resource record, or the QTYPE of a question
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RecordType {
+    A,
+    NS,
+    CNAME,
+    SOA,
+    PTR,
+    MX,
+    TXT,
+    AAAA,
+    SRV,
+    OPT,
+    Unknown(u16),
+}
+
+impl RecordType {
+    pub fn from_wire(n: u16) -> RecordType {
+        match n {
+            1 => RecordType::A,
+            2 => RecordType::NS,
+            5 => RecordType::CNAME,
+            6 => RecordType::SOA,
+            12 => RecordType::PTR,
+            15 => RecordType::MX,
+            16 => RecordType::TXT,
+            28 => RecordType::AAAA,
+            33 => RecordType::SRV,
+            41 => RecordType::OPT,
+            n => RecordType::Unknown(n),
+        }
+    }
+
+    pub fn to_wire(self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::NS => 2,
+            RecordType::CNAME => 5,
+            RecordType::SOA => 6,
+            RecordType::PTR => 12,
+            RecordType::MX => 15,
+            RecordType::TXT => 16,
+            RecordType::AAAA => 28,
+            RecordType::SRV => 33,
+            RecordType::OPT => 41,
+            RecordType::Unknown(n) => n,
+        }
+    }
+}
+
+impl fmt::Display for RecordType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordType::Unknown(n) => write!(f, "TYPE{}", n),
+            known => write!(f, "{:?}", known),
+        }
+    }
+}
+
+impl FromStr for RecordType {
+    type Err = PresentationError;
+
+    /// Parse a mnemonic like `MX`, or the RFC 3597 `TYPE123` form
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_ascii_uppercase();
+
+        let rtype = match upper.as_str() {
+            "A" => RecordType::A,
+            "NS" => RecordType::NS,
+            "CNAME" => RecordType::CNAME,
+            "SOA" => RecordType::SOA,
+            "PTR" => RecordType::PTR,
+            "MX" => RecordType::MX,
+            "TXT" => RecordType::TXT,
+            "AAAA" => RecordType::AAAA,
+            "SRV" => RecordType::SRV,
+            "OPT" => RecordType::OPT,
+            _ => upper
+                .strip_prefix("TYPE")
+                .and_then(|n| n.parse::<u16>().ok())
+                .map(RecordType::from_wire)
+                .ok_or_else(|| PresentationError::UnknownType(s.to_string()))?,
+        };
+
+        Ok(rtype)
+    }
+}
+
+/// Parse a TTL, either as plain seconds or with units as in `1h30m`, where
+/// the units are `w`, `d`, `h`, `m` and `s`
+pub fn parse_ttl(s: &str) -> Option<u32> {
+    if let Ok(seconds) = s.parse() {
+        return Some(seconds);
+    }
+
+    let mut total: u32 = 0;
+    let mut digits = String::new();
+
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            'w' => 604800,
+            'd' => 86400,
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return None,
+        };
+
+        let value = std::mem::take(&mut digits).parse::<u32>().ok()?;
+        total = total.checked_add(value.checked_mul(unit)?)?;
+    }
+
+    digits.is_empty().then_some(total)
+}
+
+/// Decode the escapes in a character-string, `\X` for a literal `X` and
+/// `\DDD` for a byte given in decimal
+fn unescape(s: &str) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let mut bytes = s.bytes();
+
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            out.push(byte);
+            continue;
+        }
+
+        let next = bytes.next()?;
+        if !next.is_ascii_digit() {
+            out.push(next);
+            continue;
+        }
+
+        let digits = [next, bytes.next()?, bytes.next()?];
+        let value = std::str::from_utf8(&digits).ok()?.parse::<u8>().ok()?;
+        out.push(value);
+    }
+
+    Some(out)
+}
+
+/// The CLASS of a resource record, or the QCLASS of a question
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RecordClass {
+    IN,
+    CH,
+    HS,
+    NONE,
+    ANY,
+    Unknown(u16),
+}
+
+impl RecordClass {
+    pub fn from_wire(n: u16) -> RecordClass {
+        match n {
+            1 => RecordClass::IN,
+            3 => RecordClass::CH,
+            4 => RecordClass::HS,
+            254 => RecordClass::NONE,
+            255 => RecordClass::ANY,
+            n => RecordClass::Unknown(n),
+        }
+    }
+
+    pub fn to_wire(self) -> u16 {
+        match self {
+            RecordClass::IN => 1,
+            RecordClass::CH => 3,
+            RecordClass::HS => 4,
+            RecordClass::NONE => 254,
+            RecordClass::ANY => 255,
+            RecordClass::Unknown(n) => n,
+        }
+    }
+}
+
+impl fmt::Display for RecordClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordClass::Unknown(n) => write!(f, "CLASS{}", n),
+            known => write!(f, "{:?}", known),
+        }
+    }
+}
+
+impl FromStr for RecordClass {
+    type Err = PresentationError;
+
+    /// Parse a mnemonic like `IN`, or the RFC 3597 `CLASS32` form
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_ascii_uppercase();
+
+        let class = match upper.as_str() {
+            "IN" => RecordClass::IN,
+            "CH" => RecordClass::CH,
+            "HS" => RecordClass::HS,
+            "NONE" => RecordClass::NONE,
+            "ANY" => RecordClass::ANY,
+            _ => upper
+                .strip_prefix("CLASS")
+                .and_then(|n| n.parse::<u16>().ok())
+                .map(RecordClass::from_wire)
+                .ok_or_else(|| PresentationError::UnknownClass(s.to_string()))?,
+        };
+
+        Ok(class)
+    }
+}
+
+/// The RDATA of a resource record, decoded according to its type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RData {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    NS(DomainName),
+    CNAME(DomainName),
+    PTR(DomainName),
+    MX {
+        preference: u16,
+        exchange: DomainName,
+    },
+    SOA {
+        mname: DomainName,
+        rname: DomainName,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    /// One or more character-strings
+    TXT(Vec<Vec<u8>>),
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: DomainName,
+    },
+    /// The options of an EDNS(0) pseudo-record
+    OPT(Vec<EdnsOption>),
+    /// Data of a type we don't decode, kept exactly as received so it can be
+    /// passed on untouched, RFC 3597
+    Unknown { rtype: u16, bytes: Vec<u8> },
+}
+
+impl RData {
+    /// The record type this data belongs to
+    pub fn rtype(&self) -> RecordType {
+        match self {
+            RData::A(_) => RecordType::A,
+            RData::AAAA(_) => RecordType::AAAA,
+            RData::NS(_) => RecordType::NS,
+            RData::CNAME(_) => RecordType::CNAME,
+            RData::PTR(_) => RecordType::PTR,
+            RData::MX { .. } => RecordType::MX,
+            RData::SOA { .. } => RecordType::SOA,
+            RData::TXT(_) => RecordType::TXT,
+            RData::SRV { .. } => RecordType::SRV,
+            RData::OPT(_) => RecordType::OPT,
+            RData::Unknown { rtype, .. } => RecordType::from_wire(*rtype),
+        }
+    }
+
+    /// Parse the RFC 3597 generic form, `\# <length> <hex>`
+    ///
+    /// The hex may be split up by whitespace. Types we know about are decoded
+    /// into their typed form, anything else is kept as `Unknown`.
+    pub fn from_generic(rtype: RecordType, text: &str) -> Result<RData, PresentationError> {
+        let mut tokens = text.split_whitespace();
+
+        if tokens.next() != Some("\\#") {
+            return Err(PresentationError::NotGeneric);
+        }
+
+        let expected = tokens
+            .next()
+            .and_then(|len| len.parse::<u16>().ok())
+            .ok_or(PresentationError::BadLength)?;
+
+        // from_str_radix would take a sign too, so check the digits first
+        let hex: String = tokens.collect();
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) || hex.len() & 1 != 0 {
+            return Err(PresentationError::BadHex);
+        }
+
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| PresentationError::BadHex)?;
+
+        if bytes.len() != expected as usize {
+            return Err(PresentationError::LengthMismatch {
+                expected: expected as usize,
+                found: bytes.len(),
+            });
+        }
+
+        RData::from_bytes(rtype, &bytes).map_err(|_| PresentationError::BadData(rtype))
+    }
+
+    /// Parse the data of a record from the fields of a zone file line
+    ///
+    /// Names may be relative to `origin`. Quoted fields should come with
+    /// their quotes already removed but escapes left in. Any type can also
+    /// be given in the RFC 3597 generic form.
+    pub fn from_presentation(
+        rtype: RecordType,
+        fields: &[String],
+        origin: &DomainName,
+    ) -> Result<RData, PresentationError> {
+        if fields.first().is_some_and(|f| f == "\\#") {
+            return RData::from_generic(rtype, &fields.join(" "));
+        }
+
+        let bad = |text: &str| PresentationError::BadField {
+            rtype,
+            text: text.to_string(),
+        };
+        let name = |text: &String| DomainName::from_relative(text, origin).map_err(|_| bad(text));
+        let number = |text: &String| text.parse::<u32>().map_err(|_| bad(text));
+        let short = |text: &String| text.parse::<u16>().map_err(|_| bad(text));
+
+        let rdata = match (rtype, fields) {
+            (RecordType::A, [addr]) => RData::A(addr.parse().map_err(|_| bad(addr))?),
+            (RecordType::AAAA, [addr]) => RData::AAAA(addr.parse().map_err(|_| bad(addr))?),
+            (RecordType::NS, [target]) => RData::NS(name(target)?),
+            (RecordType::CNAME, [target]) => RData::CNAME(name(target)?),
+            (RecordType::PTR, [target]) => RData::PTR(name(target)?),
+            (RecordType::MX, [preference, exchange]) => RData::MX {
+                preference: short(preference)?,
+                exchange: name(exchange)?,
+            },
+            (RecordType::SOA, [mname, rname, serial, refresh, retry, expire, minimum]) => {
+                RData::SOA {
+                    mname: name(mname)?,
+                    rname: name(rname)?,
+                    serial: number(serial)?,
+                    refresh: parse_ttl(refresh).ok_or_else(|| bad(refresh))?,
+                    retry: parse_ttl(retry).ok_or_else(|| bad(retry))?,
+                    expire: parse_ttl(expire).ok_or_else(|| bad(expire))?,
+                    minimum: parse_ttl(minimum).ok_or_else(|| bad(minimum))?,
+                }
+            }
+            (RecordType::TXT, strings) if !strings.is_empty() => RData::TXT(
+                strings
+                    .iter()
+                    .map(|s| unescape(s).filter(|s| s.len() <= 255).ok_or_else(|| bad(s)))
+                    .collect::<Result<_, _>>()?,
+            ),
+            (RecordType::SRV, [priority, weight, port, target]) => RData::SRV {
+                priority: short(priority)?,
+                weight: short(weight)?,
+                port: short(port)?,
+                target: name(target)?,
+            },
+            (RecordType::Unknown(_) | RecordType::OPT, _) => {
+                return Err(PresentationError::NotGeneric)
+            }
+            _ => return Err(PresentationError::FieldCount(rtype)),
+        };
+
+        Ok(rdata)
+    }
+
+    /// Decode RDATA held on its own, outside of any message
+    pub fn from_bytes(rtype: RecordType, bytes: &[u8]) -> Result<RData, WireError> {
+        let mut buf = BytePacketBuffer::from_bytes(bytes);
+
+        RData::from_buffer(&mut buf, rtype, bytes.len() as u16)
+    }
+
+    /// Read `rdlength` bytes of RDATA for a record of type `rtype`
+    ///
+    /// Names inside the data may be compressed against the rest of the
+    /// message, so this reads from the message buffer rather than a copy of
+    /// the RDATA alone.
+    pub fn from_buffer(
+        buf: &mut BytePacketBuffer,
+        rtype: RecordType,
+        rdlength: u16,
+    ) -> Result<RData, WireError> {
+        let start = buf.pos();
+        let end = start + rdlength as usize;
+
+        if buf.len() < end {
+            return Err(WireError::RdataOverrun {
+                offset: start,
+                rdlength,
+                section: buf.section,
+            });
+        }
+
+        let rdata = match rtype {
+            RecordType::A => {
+                let raw = buf.read_u32()?;
+                RData::A(Ipv4Addr::from(raw))
+            }
+            RecordType::AAAA => {
+                let mut raw = [0; 16];
+                raw.copy_from_slice(buf.get_range(start, 16)?);
+                buf.step(16)?;
+                RData::AAAA(Ipv6Addr::from(raw))
+            }
+            RecordType::NS => RData::NS(buf.read_qname()?),
+            RecordType::CNAME => RData::CNAME(buf.read_qname()?),
+            RecordType::PTR => RData::PTR(buf.read_qname()?),
+            RecordType::MX => RData::MX {
+                preference: buf.read_u16()?,
+                exchange: buf.read_qname()?,
+            },
+            RecordType::SOA => RData::SOA {
+                mname: buf.read_qname()?,
+                rname: buf.read_qname()?,
+                serial: buf.read_u32()?,
+                refresh: buf.read_u32()?,
+                retry: buf.read_u32()?,
+                expire: buf.read_u32()?,
+                minimum: buf.read_u32()?,
+            },
+            RecordType::TXT => {
+                let mut strings = vec![];
+
+                while buf.pos() < end {
+                    let len = buf.read()? as usize;
+                    strings.push(buf.read_bytes(len)?);
+                }
+
+                RData::TXT(strings)
+            }
+            RecordType::SRV => RData::SRV {
+                priority: buf.read_u16()?,
+                weight: buf.read_u16()?,
+                port: buf.read_u16()?,
+                target: buf.read_qname()?,
+            },
+            RecordType::OPT => RData::OPT(EdnsOption::list_from_buffer(buf, end)?),
+            RecordType::Unknown(n) => RData::Unknown {
+                rtype: n,
+                bytes: buf.read_bytes(rdlength as usize)?,
+            },
+        };
+
+        // The decoded fields have to account for every byte of RDATA, no
+        // more and no less
+        if buf.pos() != end {
+            return Err(WireError::BadRdata {
+                offset: start,
+                rtype: rtype.to_wire(),
+                section: buf.section,
+            });
+        }
+
+        Ok(rdata)
+    }
+
+    /// Write the RDATA, without the length in front of it
+    ///
+    /// Only the types defined in RFC 1035 get their names compressed, RFC 3597
+    /// section 4 forbids it for anything newer.
+    pub fn to_buffer(&self, buf: &mut BytePacketBuffer) {
+        match self {
+            RData::A(addr) => buf.write_bytes(&addr.octets()),
+            RData::AAAA(addr) => buf.write_bytes(&addr.octets()),
+            RData::NS(name) | RData::CNAME(name) | RData::PTR(name) => buf.write_qname(name),
+            RData::MX {
+                preference,
+                exchange,
+            } => {
+                buf.write_u16(*preference);
+                buf.write_qname(exchange);
+            }
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                buf.write_qname(mname);
+                buf.write_qname(rname);
+                buf.write_u32(*serial);
+                buf.write_u32(*refresh);
+                buf.write_u32(*retry);
+                buf.write_u32(*expire);
+                buf.write_u32(*minimum);
+            }
+            RData::TXT(strings) => {
+                for s in strings {
+                    buf.write(s.len() as u8);
+                    buf.write_bytes(s);
+                }
+            }
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                buf.write_u16(*priority);
+                buf.write_u16(*weight);
+                buf.write_u16(*port);
+                buf.write_qname_uncompressed(target);
+            }
+            RData::OPT(options) => EdnsOption::list_to_buffer(options, buf),
+            RData::Unknown { bytes, .. } => buf.write_bytes(bytes),
+        }
+    }
+}
+
+impl fmt::Display for RData {
+    /// Presentation format of the data, as it would appear in a zone file
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RData::A(addr) => write!(f, "{}", addr),
+            RData::AAAA(addr) => write!(f, "{}", addr),
+            RData::NS(name) | RData::CNAME(name) | RData::PTR(name) => write!(f, "{}", name),
+            RData::MX {
+                preference,
+                exchange,
+            } => write!(f, "{} {}", preference, exchange),
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                mname, rname, serial, refresh, retry, expire, minimum
+            ),
+            RData::TXT(strings) => {
+                for (i, s) in strings.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" ")?;
+                    }
+
+                    f.write_str("\"")?;
+                    for &byte in s {
+                        match byte {
+                            b'"' | b'\\' => write!(f, "\\{}", byte as char)?,
+                            0x20..=0x7E => write!(f, "{}", byte as char)?,
+                            _ => write!(f, "\\{:03}", byte)?,
+                        }
+                    }
+                    f.write_str("\"")?;
+                }
+
+                Ok(())
+            }
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => write!(f, "{} {} {} {}", priority, weight, port, target),
+            RData::OPT(options) => {
+                for (i, option) in options.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" ")?;
+                    }
+                    write!(f, "{}", option)?;
+                }
+
+                Ok(())
+            }
+            RData::Unknown { bytes, .. } => {
+                write!(f, "\\# {}", bytes.len())?;
+
+                if !bytes.is_empty() {
+                    f.write_str(" ")?;
+                }
+                for byte in bytes {
+                    write!(f, "{:02x}", byte)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DNSResource;
+
+    fn name(s: &str) -> DomainName {
+        s.parse().unwrap()
+    }
+
+    /// Write `rdata` after `example.com.`, so names in it can point back
+    /// there, and give the buffer and where the RDATA starts
+    fn write_after_name(rdata: &RData) -> (BytePacketBuffer, usize) {
+        let mut buf = BytePacketBuffer::new();
+        buf.write_qname(&name("example.com."));
+
+        let start = buf.len();
+        rdata.to_buffer(&mut buf);
+
+        (buf, start)
+    }
+
+    fn read_back(buf: &BytePacketBuffer, start: usize, rdata: &RData) -> RData {
+        let mut buf = BytePacketBuffer::from_bytes(buf.as_bytes());
+        buf.seek(start).unwrap();
+        let rdlength = (buf.len() - start) as u16;
+
+        RData::from_buffer(&mut buf, rdata.rtype(), rdlength).unwrap()
+    }
+
+    #[test]
+    fn compresses_names_in_mx_and_soa() {
+        let mx = RData::MX {
+            preference: 10,
+            exchange: name("mail.example.com."),
+        };
+        let (buf, start) = write_after_name(&mx);
+        // The preference, then mail and a pointer
+        assert_eq!(buf.len() - start, 2 + 5 + 2);
+        assert_eq!(read_back(&buf, start, &mx), mx);
+
+        let soa = RData::SOA {
+            mname: name("ns1.example.com."),
+            rname: name("hostmaster.example.com."),
+            serial: 2024010101,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300,
+        };
+        let (buf, start) = write_after_name(&soa);
+        assert_eq!(buf.len() - start, (4 + 2) + (11 + 2) + 5 * 4);
+        assert_eq!(read_back(&buf, start, &soa), soa);
+    }
+
+    #[test]
+    fn writes_srv_targets_uncompressed() {
+        let srv = RData::SRV {
+            priority: 1,
+            weight: 2,
+            port: 5060,
+            target: name("sip.example.com."),
+        };
+        let (buf, start) = write_after_name(&srv);
+
+        assert_eq!(buf.len() - start, 6 + name("sip.example.com.").wire_len());
+        assert_eq!(read_back(&buf, start, &srv), srv);
+    }
+
+    #[test]
+    fn rejects_rdata_that_doesnt_fill_rdlength() {
+        let bad_rdata = |rtype, bytes: &[u8]| {
+            matches!(
+                RData::from_bytes(rtype, bytes),
+                Err(WireError::BadRdata { .. })
+            )
+        };
+
+        assert!(bad_rdata(RecordType::A, &[192, 0, 2, 1, 0]));
+        assert!(bad_rdata(RecordType::NS, b"\x02ns\x00\x00"));
+        assert!(bad_rdata(RecordType::MX, b"\x00\x0a\x04mail\x00\xff"));
+
+        // Running out before rdlength does is caught as truncation
+        assert!(matches!(
+            RData::from_bytes(RecordType::AAAA, &[0; 4]),
+            Err(WireError::Truncated { .. })
+        ));
+    }
+
+    #[test]
+    fn fills_in_rdlength_on_write() {
+        let rr = DNSResource {
+            name: name("example.com."),
+            class: RecordClass::IN,
+            ttl: 300,
+            rdata: RData::TXT(vec![b"hello".to_vec(), b"world".to_vec()]),
+        };
+
+        let mut buf = BytePacketBuffer::new();
+        rr.to_buffer(&mut buf);
+
+        // Name, type, class and TTL come before the length
+        let len_pos = name("example.com.").wire_len() + 8;
+        let bytes = buf.as_bytes();
+        let rdlength = u16::from_be_bytes([bytes[len_pos], bytes[len_pos + 1]]);
+        assert_eq!(rdlength as usize, bytes.len() - len_pos - 2);
+        assert_eq!(rdlength, 12);
+
+        let mut buf = BytePacketBuffer::from_bytes(bytes);
+        assert_eq!(DNSResource::from_buffer(&mut buf).unwrap().rdata, rr.rdata);
+    }
+
+    #[test]
+    fn parses_generic_rdata() {
+        assert_eq!(
+            RData::from_generic(RecordType::A, r"\# 4 c0000201").unwrap(),
+            RData::A(Ipv4Addr::new(192, 0, 2, 1))
+        );
+        assert_eq!(
+            RData::from_generic(RecordType::Unknown(65280), r"\# 5 0a 0b0c 0d0E").unwrap(),
+            RData::Unknown {
+                rtype: 65280,
+                bytes: vec![0x0a, 0x0b, 0x0c, 0x0d, 0x0e],
+            }
+        );
+        assert_eq!(
+            RData::from_generic(RecordType::Unknown(65280), r"\# 0").unwrap(),
+            RData::Unknown {
+                rtype: 65280,
+                bytes: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_generic_rdata() {
+        let unknown = RecordType::Unknown(65280);
+
+        assert_eq!(
+            RData::from_generic(unknown, r"\# 3 0a0b"),
+            Err(PresentationError::LengthMismatch {
+                expected: 3,
+                found: 2,
+            })
+        );
+        assert_eq!(
+            RData::from_generic(unknown, "# 2 0a0b"),
+            Err(PresentationError::NotGeneric)
+        );
+        assert_eq!(
+            RData::from_generic(unknown, r"\# two 0a0b"),
+            Err(PresentationError::BadLength)
+        );
+        assert_eq!(
+            RData::from_generic(unknown, r"\# 1 0a0"),
+            Err(PresentationError::BadHex)
+        );
+        // Signs would get past from_str_radix
+        assert_eq!(
+            RData::from_generic(unknown, r"\# 2 +f0a"),
+            Err(PresentationError::BadHex)
+        );
+        assert_eq!(
+            RData::from_generic(RecordType::A, r"\# 3 c00002"),
+            Err(PresentationError::BadData(RecordType::A))
+        );
+    }
+
+    #[test]
+    fn displays_generic_rdata() {
+        let unknown = RData::Unknown {
+            rtype: 65280,
+            bytes: vec![0x0a, 0xff, 0x00],
+        };
+        assert_eq!(unknown.to_string(), r"\# 3 0aff00");
+
+        let empty = RData::Unknown {
+            rtype: 65280,
+            bytes: vec![],
+        };
+        assert_eq!(empty.to_string(), r"\# 0");
+
+        let parsed = RData::from_generic(RecordType::Unknown(65280), &unknown.to_string());
+        assert_eq!(parsed.unwrap(), unknown);
+    }
+
+    #[test]
+    fn passes_unknown_types_through_untouched() {
+        // Looks like a compressed name, but must not be treated as one
+        let bytes = [0x03, b'f', b'o', b'o', 0xc0, 0x00];
+        let rdata = RData::from_bytes(RecordType::Unknown(65280), &bytes).unwrap();
+
+        let mut buf = BytePacketBuffer::new();
+        rdata.to_buffer(&mut buf);
+        assert_eq!(buf.as_bytes(), bytes);
+    }
+}
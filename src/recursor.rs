@@ -0,0 +1,906 @@
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    net::{Ipv4Addr, SocketAddr},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bailiwick,
+    config::Config,
+    edns::{Edns, EDNS_PAYLOAD_SIZE},
+    name::DomainName,
+    rdata::{RData, RecordClass, RecordType},
+    upstream::{Upstream, UpstreamError},
+    DNSMessage, DNSQuery, OPCODE, RCODE,
+};
+
+/// Most referrals we follow for one name before giving up
+const MAX_REFERRALS: usize = 30;
+
+/// Most CNAMEs we follow for one query
+pub const MAX_CNAMES: usize = 8;
+
+/// Most minimised queries sent for one name, after which the rest of the
+/// name goes out in full, RFC 9156 section 2.3
+const MAX_MINIMISE_STEPS: usize = 10;
+
+/// How deep lookups of name server addresses may nest, since finding one
+/// name server can mean finding another first
+const MAX_DEPTH: usize = 4;
+
+/// Ways iterative resolution can fail
+#[derive(Debug, thiserror::Error)]
+pub enum ResolveError {
+    #[error(transparent)]
+    Upstream(#[from] UpstreamError),
+    #[error("no usable name server for {0}")]
+    NoServers(DomainName),
+    #[error("too many referrals resolving {0}")]
+    TooManyReferrals(DomainName),
+    #[error("CNAME chain from {0} is too long")]
+    TooManyCnames(DomainName),
+}
+
+/// How much of the name we are resolving to show each server, RFC 9156
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QnameMinimisation {
+    /// Send the full name to every server
+    Off,
+    /// Only ever reveal one label more than the server needs to see. An
+    /// NXDOMAIN part way down is taken to mean nothing exists below it
+    Strict,
+    /// As strict, but fall back to the full name when a server fails a
+    /// minimised query or wrongly answers NXDOMAIN for an empty
+    /// non-terminal
+    Relaxed,
+}
+
+impl FromStr for QnameMinimisation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(QnameMinimisation::Off),
+            "strict" => Ok(QnameMinimisation::Strict),
+            "relaxed" => Ok(QnameMinimisation::Relaxed),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for QnameMinimisation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            QnameMinimisation::Off => "off",
+            QnameMinimisation::Strict => "strict",
+            QnameMinimisation::Relaxed => "relaxed",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// A name server for a zone and the addresses we know it by
+#[derive(Debug, Clone)]
+struct NameServer {
+    name: DomainName,
+    /// Only IPv4, the upstream socket is bound to an IPv4 address
+    addrs: Vec<Ipv4Addr>,
+}
+
+/// The name servers a zone has been delegated to
+#[derive(Debug, Clone)]
+struct Delegation {
+    zone: DomainName,
+    servers: Vec<NameServer>,
+    /// `None` for the root hints, which never run out
+    expires: Option<Instant>,
+}
+
+impl Delegation {
+    fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|t| t <= Instant::now())
+    }
+}
+
+/// What one name server told us
+enum Step {
+    /// An answer, or an authoritative statement that there isn't one
+    Answer(DNSMessage),
+    /// A referral to name servers closer to the name
+    Referral(Delegation),
+}
+
+/// Resolves names itself, starting from the root servers and following
+/// referrals down to a server that has the answer
+///
+/// Delegations learnt along the way are kept until their NS records expire,
+/// so later lookups start as close to the name as possible.
+#[derive(Debug)]
+pub struct Recursor {
+    root: Delegation,
+    /// Port authoritative servers are asked on, 53 everywhere but in tests
+    port: u16,
+    minimisation: QnameMinimisation,
+    delegations: Mutex<HashMap<DomainName, Delegation>>,
+}
+
+impl Recursor {
+    pub fn new(config: &Config) -> io::Result<Recursor> {
+        Ok(Recursor {
+            root: load_hints(&config.root_hints)?,
+            port: config.auth_port,
+            minimisation: config.qname_minimisation,
+            delegations: Mutex::default(),
+        })
+    }
+
+    fn delegations(&self) -> std::sync::MutexGuard<'_, HashMap<DomainName, Delegation>> {
+        self.delegations.lock().expect("delegation lock poisoned")
+    }
+
+    /// The delegation closest to `name` that we know of
+    fn closest_delegation(&self, name: &DomainName) -> Delegation {
+        let mut delegations = self.delegations();
+        let mut zone = Some(name.clone());
+
+        while let Some(current) = zone {
+            match delegations.get(&current) {
+                Some(delegation) if delegation.is_expired() => {
+                    delegations.remove(&current);
+                }
+                Some(delegation) => return delegation.clone(),
+                None => {}
+            }
+
+            zone = current.parent();
+        }
+
+        self.root.clone()
+    }
+
+    /// Answer `query`, following any CNAMEs to their end
+    pub fn resolve(
+        &self,
+        upstream: &Upstream,
+        query: &DNSQuery,
+        dnssec_ok: bool,
+    ) -> Result<DNSMessage, ResolveError> {
+        self.resolve_at(upstream, query, dnssec_ok, 0)
+    }
+
+    fn resolve_at(
+        &self,
+        upstream: &Upstream,
+        query: &DNSQuery,
+        dnssec_ok: bool,
+        depth: usize,
+    ) -> Result<DNSMessage, ResolveError> {
+        let mut chain = vec![];
+        let mut current = query.clone();
+
+        for _ in 0..MAX_CNAMES {
+            let mut response = self.resolve_name(upstream, &current, dnssec_ok, depth)?;
+
+            // Follow the chain as far as this response takes it, a loop
+            // within it just runs out of steps
+            let mut name = current.qname.clone();
+            for _ in 0..MAX_CNAMES {
+                match cname_target(&response, &name, current.qtype) {
+                    Some(target) => name = target,
+                    None => break,
+                }
+            }
+
+            let found = response
+                .ans
+                .iter()
+                .any(|rr| rr.name == name && rr.rtype() == current.qtype);
+
+            let negative = response.header.rcode == RCODE::NameErr
+                || response.nsr.iter().any(|rr| rr.rtype() == RecordType::SOA);
+
+            if found || negative || name == current.qname {
+                chain.append(&mut response.ans);
+                response.ans = chain;
+                response.queries = vec![query.clone()];
+
+                return Ok(response);
+            }
+
+            // The chain leads somewhere this server doesn't answer for, so
+            // start again from the target
+            println!("Following CNAME from {} to {}", current.qname, name);
+
+            chain.append(&mut response.ans);
+            current.qname = name;
+        }
+
+        Err(ResolveError::TooManyCnames(query.qname.clone()))
+    }
+
+    /// Find the server responsible for a name and ask it, without following
+    /// CNAMEs
+    ///
+    /// With QNAME minimisation each server on the way down is only asked
+    /// about the name one label below the zone it serves, as an A query,
+    /// until we reach the zone the full name lives in.
+    fn resolve_name(
+        &self,
+        upstream: &Upstream,
+        query: &DNSQuery,
+        dnssec_ok: bool,
+        depth: usize,
+    ) -> Result<DNSMessage, ResolveError> {
+        let mut delegation = self.closest_delegation(&query.qname);
+
+        // How many labels of the name we have seen to exist so far
+        let mut known = delegation.zone.label_count();
+        let mut minimise = self.minimisation != QnameMinimisation::Off;
+        let mut steps = 0;
+
+        for _ in 0..MAX_REFERRALS + MAX_MINIMISE_STEPS {
+            let minimised =
+                (minimise && known + 1 < query.qname.label_count() && steps < MAX_MINIMISE_STEPS)
+                    .then(|| DNSQuery {
+                        qname: query.qname.ancestor(known + 1),
+                        qtype: RecordType::A,
+                        qclass: query.qclass,
+                    });
+            let sent = minimised.as_ref().unwrap_or(query);
+            steps += minimised.is_some() as usize;
+
+            let step = match self.ask(upstream, &delegation, sent, dnssec_ok, depth) {
+                Err(e)
+                    if minimised.is_some() && self.minimisation == QnameMinimisation::Relaxed =>
+                {
+                    println!(
+                        "Minimised query for {} failed, sending {} in full: {}",
+                        sent.qname, query.qname, e
+                    );
+
+                    minimise = false;
+                    continue;
+                }
+                result => result?,
+            };
+
+            match step {
+                Step::Referral(next) => {
+                    println!("Referred from {} to {}", delegation.zone, next.zone);
+
+                    self.delegations().insert(next.zone.clone(), next.clone());
+                    known = next.zone.label_count();
+                    delegation = next;
+                }
+                Step::Answer(response) if minimised.is_none() => return Ok(response),
+                Step::Answer(response) if response.header.rcode == RCODE::NameErr => {
+                    if self.minimisation == QnameMinimisation::Strict {
+                        // Nothing can exist below a name that doesn't,
+                        // RFC 8020
+                        return Ok(response);
+                    }
+
+                    // Some servers get this wrong for empty non-terminals
+                    println!(
+                        "{} reported as missing, sending {} in full",
+                        sent.qname, query.qname
+                    );
+                    minimise = false;
+                }
+                // The name exists, and the zone cut is further down if
+                // there is one at all
+                Step::Answer(_) => known += 1,
+            }
+        }
+
+        Err(ResolveError::TooManyReferrals(query.qname.clone()))
+    }
+
+    /// Put `query` to the servers for `delegation` one at a time until one
+    /// gives a useful reply
+    fn ask(
+        &self,
+        upstream: &Upstream,
+        delegation: &Delegation,
+        query: &DNSQuery,
+        dnssec_ok: bool,
+        depth: usize,
+    ) -> Result<Step, ResolveError> {
+        let mut message = DNSMessage::new();
+        message.header.opcode = OPCODE::QUERY;
+        message.header.rd = false;
+        message.header.qdcount = 1;
+        message.queries = vec![query.clone()];
+
+        let mut edns = Edns::new(EDNS_PAYLOAD_SIZE);
+        edns.set_dnssec_ok(dnssec_ok);
+        message.edns = Some(edns);
+
+        let mut error = ResolveError::NoServers(delegation.zone.clone());
+
+        for server in &delegation.servers {
+            let addrs = if server.addrs.is_empty() {
+                self.find_addresses(upstream, delegation, &server.name, depth)
+            } else {
+                server.addrs.clone()
+            };
+
+            for addr in addrs {
+                let addr = SocketAddr::from((addr, self.port)).to_string();
+
+                match upstream.exchange(&addr, &message) {
+                    Ok(mut response) => {
+                        bailiwick::scrub(&mut response, query, &delegation.zone);
+
+                        match classify(delegation, query, response) {
+                            Some(step) => return Ok(step),
+                            None => {
+                                println!("Unusable reply from {} for {}", addr, delegation.zone)
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to query {}: {}", addr, e);
+                        error = e.into();
+                    }
+                }
+            }
+        }
+
+        Err(error)
+    }
+
+    /// Look up the addresses of a name server that came without glue,
+    /// remembering them with the delegation
+    fn find_addresses(
+        &self,
+        upstream: &Upstream,
+        delegation: &Delegation,
+        name: &DomainName,
+        depth: usize,
+    ) -> Vec<Ipv4Addr> {
+        if depth >= MAX_DEPTH {
+            return vec![];
+        }
+
+        let query = DNSQuery {
+            qname: name.clone(),
+            qtype: RecordType::A,
+            qclass: RecordClass::IN,
+        };
+
+        let addrs = match self.resolve_at(upstream, &query, false, depth + 1) {
+            Ok(response) => response
+                .ans
+                .iter()
+                .filter_map(|rr| match rr.rdata {
+                    RData::A(addr) => Some(addr),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                eprintln!("Failed to find address of name server {}: {}", name, e);
+                vec![]
+            }
+        };
+
+        if let Some(known) = self.delegations().get_mut(&delegation.zone) {
+            for server in known.servers.iter_mut().filter(|s| s.name == *name) {
+                server.addrs = addrs.clone();
+            }
+        }
+
+        addrs
+    }
+}
+
+/// Where the CNAME owned by `name` in `response` points, unless the CNAME
+/// itself was asked for
+fn cname_target(response: &DNSMessage, name: &DomainName, qtype: RecordType) -> Option<DomainName> {
+    if qtype == RecordType::CNAME {
+        return None;
+    }
+
+    response.ans.iter().find_map(|rr| match &rr.rdata {
+        RData::CNAME(target) if rr.name == *name => Some(target.clone()),
+        _ => None,
+    })
+}
+
+/// Work out what a server's reply to `query` amounts to, `None` if it is no
+/// use to us: an error, or a lame reply that neither answers nor gets us
+/// any closer
+fn classify(delegation: &Delegation, query: &DNSQuery, response: DNSMessage) -> Option<Step> {
+    if !matches!(response.header.rcode, RCODE::NoErr | RCODE::NameErr) {
+        return None;
+    }
+
+    if response.header.aa || response.header.rcode == RCODE::NameErr || !response.ans.is_empty() {
+        return Some(Step::Answer(response));
+    }
+
+    // A referral has to be for a zone below the one we asked about, or we
+    // could end up going round in circles
+    let zone = response.nsr.iter().find_map(|rr| match rr.rdata {
+        RData::NS(_)
+            if rr.name.is_subdomain_of(&delegation.zone)
+                && rr.name != delegation.zone
+                && query.qname.is_subdomain_of(&rr.name) =>
+        {
+            Some(rr.name.clone())
+        }
+        _ => None,
+    });
+
+    let Some(zone) = zone else {
+        // Some servers leave AA off their NODATA answers
+        let has_soa = response.nsr.iter().any(|rr| rr.rtype() == RecordType::SOA);
+        return has_soa.then_some(Step::Answer(response));
+    };
+
+    let mut ttl = u32::MAX;
+    let servers = response
+        .nsr
+        .iter()
+        .filter(|rr| rr.name == zone)
+        .filter_map(|rr| match &rr.rdata {
+            RData::NS(name) => {
+                ttl = ttl.min(rr.ttl);
+                Some(name)
+            }
+            _ => None,
+        })
+        .map(|name| NameServer {
+            name: name.clone(),
+            addrs: response
+                .arc
+                .iter()
+                .filter(|glue| glue.name == *name)
+                .filter_map(|glue| match glue.rdata {
+                    RData::A(addr) => Some(addr),
+                    _ => None,
+                })
+                .collect(),
+        })
+        .collect();
+
+    Some(Step::Referral(Delegation {
+        zone,
+        servers,
+        expires: Some(Instant::now() + Duration::from_secs(ttl as u64)),
+    }))
+}
+
+/// Read the root servers out of a root hints file, as published by IANA
+///
+/// Only the NS records for the root and the A records for the servers are
+/// used. Lines are `name [ttl] [class] type data`, with `;` starting a
+/// comment.
+fn load_hints(path: &str) -> io::Result<Delegation> {
+    let bad =
+        |line: &str| io::Error::new(io::ErrorKind::InvalidData, format!("bad line {:?}", line));
+
+    let mut names = vec![];
+    let mut addrs: HashMap<DomainName, Vec<Ipv4Addr>> = HashMap::new();
+
+    for line in fs::read_to_string(path)?.lines() {
+        let line = line.split(';').next().unwrap_or_default();
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+
+        let Some((owner, rest)) = fields.split_first() else {
+            continue;
+        };
+
+        // Skip the optional TTL and class to get to the type
+        let rest = rest
+            .iter()
+            .skip_while(|f| f.parse::<u32>().is_ok() || f.eq_ignore_ascii_case("IN"))
+            .collect::<Vec<_>>();
+        let [rtype, data] = rest.as_slice() else {
+            return Err(bad(line));
+        };
+
+        let owner = owner.parse::<DomainName>().map_err(|_| bad(line))?;
+
+        match rtype.to_ascii_uppercase().as_str() {
+            "NS" if owner.is_root() => names.push(data.parse().map_err(|_| bad(line))?),
+            "A" => addrs
+                .entry(owner)
+                .or_default()
+                .push(data.parse().map_err(|_| bad(line))?),
+            _ => {}
+        }
+    }
+
+    if names.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no root name servers",
+        ));
+    }
+
+    let servers = names
+        .into_iter()
+        .map(|name: DomainName| NameServer {
+            addrs: addrs.remove(&name).unwrap_or_default(),
+            name,
+        })
+        .collect();
+
+    Ok(Delegation {
+        zone: DomainName::root(),
+        servers,
+        expires: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, sync::OnceLock, thread};
+
+    use super::*;
+    use crate::DNSResource;
+
+    /// A zone one of the stand-in servers is authoritative for
+    struct Zone {
+        apex: DomainName,
+        records: Vec<DNSResource>,
+        /// Answer NXDOMAIN for empty non-terminals, as some servers do
+        broken_ents: bool,
+    }
+
+    impl Zone {
+        fn new(apex: &str, records: Vec<DNSResource>) -> Zone {
+            let apex = apex.parse::<DomainName>().unwrap();
+            let soa = RData::SOA {
+                mname: apex.clone(),
+                rname: apex.clone(),
+                serial: 1,
+                refresh: 2,
+                retry: 3,
+                expire: 4,
+                minimum: 60,
+            };
+
+            Zone {
+                records: [vec![rr(&apex.to_string(), soa)], records].concat(),
+                apex,
+                broken_ents: false,
+            }
+        }
+
+        /// A zone served by a server that gets empty non-terminals wrong
+        fn broken(apex: &str, records: Vec<DNSResource>) -> Zone {
+            Zone {
+                broken_ents: true,
+                ..Zone::new(apex, records)
+            }
+        }
+
+        fn find(&self, name: &DomainName, rtype: RecordType) -> Vec<DNSResource> {
+            self.records
+                .iter()
+                .filter(|rr| rr.name == *name && rr.rtype() == rtype)
+                .cloned()
+                .collect()
+        }
+
+        /// Fill in the answer to the question in `message`, for a name
+        /// known to be in this zone
+        fn answer(&self, message: &mut DNSMessage) {
+            let query = message.queries[0].clone();
+
+            // Walk up to the apex, the zone cut nearest to it is the one
+            // that counts
+            let mut cut = None;
+            let mut name = Some(query.qname.clone());
+            while let Some(current) = name.filter(|name| *name != self.apex) {
+                if !self.find(&current, RecordType::NS).is_empty() {
+                    cut = Some(current.clone());
+                }
+                name = current.parent();
+            }
+
+            if let Some(cut) = cut {
+                message.nsr = self.find(&cut, RecordType::NS);
+                message.arc = message
+                    .nsr
+                    .iter()
+                    .filter_map(|rr| match &rr.rdata {
+                        RData::NS(target) => Some(self.find(target, RecordType::A)),
+                        _ => None,
+                    })
+                    .flatten()
+                    .collect();
+                return;
+            }
+
+            message.header.aa = true;
+
+            for rtype in [query.qtype, RecordType::CNAME] {
+                message.ans = self.find(&query.qname, rtype);
+                if !message.ans.is_empty() {
+                    return;
+                }
+            }
+
+            // Empty non-terminals exist too, they just have no records
+            let exists = self.records.iter().any(|rr| {
+                rr.name == query.qname
+                    || (!self.broken_ents && rr.name.is_subdomain_of(&query.qname))
+            });
+            if !exists {
+                message.header.rcode = RCODE::NameErr;
+            }
+            message.nsr = self.find(&self.apex, RecordType::SOA);
+        }
+    }
+
+    fn rr(name: &str, rdata: RData) -> DNSResource {
+        DNSResource {
+            name: name.parse().unwrap(),
+            class: RecordClass::IN,
+            ttl: 300,
+            rdata,
+        }
+    }
+
+    fn a(name: &str, addr: &str) -> DNSResource {
+        rr(name, RData::A(addr.parse().unwrap()))
+    }
+
+    fn ns(name: &str, target: &str) -> DNSResource {
+        rr(name, RData::NS(target.parse().unwrap()))
+    }
+
+    fn cname(name: &str, target: &str) -> DNSResource {
+        rr(name, RData::CNAME(target.parse().unwrap()))
+    }
+
+    /// Answer every query sent to `socket` from `zones`, the way a real
+    /// authoritative server would
+    fn stand_in(socket: UdpSocket, zones: Vec<Zone>) {
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+
+            loop {
+                let Ok((len, from)) = socket.recv_from(&mut buf) else {
+                    continue;
+                };
+                let Ok(mut message) = DNSMessage::from_wire(&buf[..len]) else {
+                    continue;
+                };
+
+                message.edns = None;
+                message.prepare_answer();
+
+                let zone = message.queries.first().and_then(|query| {
+                    zones
+                        .iter()
+                        .filter(|zone| query.qname.is_subdomain_of(&zone.apex))
+                        .max_by_key(|zone| zone.apex.label_count())
+                });
+                match zone {
+                    Some(zone) => zone.answer(&mut message),
+                    None => message.header.rcode = RCODE::Refused,
+                }
+
+                let _ = socket.send_to(&message.to_wire(), from);
+            }
+        });
+    }
+
+    /// Start the stand-in servers once for all the tests, returning the
+    /// port they all listen on
+    fn network() -> u16 {
+        static PORT: OnceLock<u16> = OnceLock::new();
+
+        *PORT.get_or_init(|| {
+            let root = UdpSocket::bind("127.0.0.2:0").expect("bind root");
+            let port = root.local_addr().expect("local addr").port();
+            let bind = |ip: &str| UdpSocket::bind((ip, port)).expect("bind stand-in");
+
+            stand_in(
+                root,
+                vec![Zone::new(
+                    ".",
+                    vec![
+                        ns("com.", "ns.tld-servers.net."),
+                        ns("net.", "ns.tld-servers.net."),
+                        a("ns.tld-servers.net.", "127.0.0.3"),
+                    ],
+                )],
+            );
+            stand_in(
+                bind("127.0.0.3"),
+                vec![
+                    Zone::new(
+                        "com.",
+                        vec![
+                            ns("example.com.", "ns1.example.com."),
+                            a("ns1.example.com.", "127.0.0.4"),
+                            ns("broken.com.", "ns1.example.com."),
+                            // No glue, the name server is in another zone
+                            // altogether
+                            ns("other.com.", "ns.hosting.net."),
+                        ],
+                    ),
+                    Zone::new(
+                        "net.",
+                        vec![
+                            a("ns.tld-servers.net.", "127.0.0.3"),
+                            ns("hosting.net.", "ns.hosting.net."),
+                            a("ns.hosting.net.", "127.0.0.5"),
+                        ],
+                    ),
+                ],
+            );
+            stand_in(
+                bind("127.0.0.4"),
+                vec![
+                    Zone::new(
+                        "example.com.",
+                        vec![
+                            a("www.example.com.", "10.0.0.1"),
+                            cname("alias.example.com.", "www.example.com."),
+                            cname("ext.example.com.", "www.other.com."),
+                            // ent.example.com. and deep.ent.example.com.
+                            // only exist as empty non-terminals
+                            a("host.deep.ent.example.com.", "10.0.0.3"),
+                        ],
+                    ),
+                    Zone::broken("broken.com.", vec![a("host.ent.broken.com.", "10.0.0.4")]),
+                ],
+            );
+            stand_in(
+                bind("127.0.0.5"),
+                vec![
+                    Zone::new("hosting.net.", vec![a("ns.hosting.net.", "127.0.0.5")]),
+                    Zone::new("other.com.", vec![a("www.other.com.", "10.0.0.2")]),
+                ],
+            );
+
+            port
+        })
+    }
+
+    fn resolve(name: &str) -> DNSMessage {
+        resolve_minimised(name, QnameMinimisation::Relaxed)
+    }
+
+    fn resolve_minimised(name: &str, minimisation: QnameMinimisation) -> DNSMessage {
+        let config = Config {
+            timeout: Duration::from_millis(500),
+            retries: 1,
+            qname_minimisation: minimisation,
+            ..Config::default()
+        };
+
+        let upstream = Upstream::new(&config).expect("upstream socket");
+        let recursor = Recursor {
+            root: Delegation {
+                zone: DomainName::root(),
+                servers: vec![NameServer {
+                    name: "a.root.".parse().unwrap(),
+                    addrs: vec![Ipv4Addr::new(127, 0, 0, 2)],
+                }],
+                expires: None,
+            },
+            port: network(),
+            minimisation: config.qname_minimisation,
+            delegations: Mutex::default(),
+        };
+        let query = DNSQuery {
+            qname: name.parse().expect("name"),
+            qtype: RecordType::A,
+            qclass: RecordClass::IN,
+        };
+
+        recursor
+            .resolve(&upstream, &query, false)
+            .expect("resolved")
+    }
+
+    fn addresses(message: &DNSMessage) -> Vec<Ipv4Addr> {
+        message
+            .ans
+            .iter()
+            .filter_map(|rr| match rr.rdata {
+                RData::A(addr) => Some(addr),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn follows_referrals_with_glue() {
+        let response = resolve("www.example.com.");
+
+        assert_eq!(response.header.rcode, RCODE::NoErr);
+        assert_eq!(addresses(&response), [Ipv4Addr::new(10, 0, 0, 1)]);
+    }
+
+    #[test]
+    fn looks_up_glueless_name_servers() {
+        let response = resolve("www.other.com.");
+
+        assert_eq!(response.header.rcode, RCODE::NoErr);
+        assert_eq!(addresses(&response), [Ipv4Addr::new(10, 0, 0, 2)]);
+    }
+
+    #[test]
+    fn chases_cnames_within_a_zone() {
+        let response = resolve("alias.example.com.");
+
+        assert_eq!(
+            response.ans[0].rdata,
+            RData::CNAME("www.example.com.".parse().unwrap())
+        );
+        assert_eq!(addresses(&response), [Ipv4Addr::new(10, 0, 0, 1)]);
+    }
+
+    #[test]
+    fn chases_cnames_across_servers() {
+        let response = resolve("ext.example.com.");
+
+        assert_eq!(
+            response.ans[0].rdata,
+            RData::CNAME("www.other.com.".parse().unwrap())
+        );
+        assert_eq!(addresses(&response), [Ipv4Addr::new(10, 0, 0, 2)]);
+    }
+
+    #[test]
+    fn passes_on_nxdomain_with_the_soa() {
+        let response = resolve("nope.example.com.");
+
+        assert_eq!(response.header.rcode, RCODE::NameErr);
+        assert!(response.ans.is_empty());
+        assert_eq!(response.nsr[0].rtype(), RecordType::SOA);
+    }
+
+    #[test]
+    fn minimised_queries_pass_empty_non_terminals() {
+        for minimisation in [
+            QnameMinimisation::Off,
+            QnameMinimisation::Strict,
+            QnameMinimisation::Relaxed,
+        ] {
+            let response = resolve_minimised("host.deep.ent.example.com.", minimisation);
+
+            assert_eq!(response.header.rcode, RCODE::NoErr, "{}", minimisation);
+            assert_eq!(addresses(&response), [Ipv4Addr::new(10, 0, 0, 3)]);
+        }
+    }
+
+    #[test]
+    fn strict_minimisation_stops_at_nxdomain() {
+        let response = resolve_minimised("host.ent.broken.com.", QnameMinimisation::Strict);
+
+        assert_eq!(response.header.rcode, RCODE::NameErr);
+        assert!(response.ans.is_empty());
+    }
+
+    #[test]
+    fn relaxed_minimisation_gets_past_broken_empty_non_terminals() {
+        for minimisation in [QnameMinimisation::Off, QnameMinimisation::Relaxed] {
+            let response = resolve_minimised("host.ent.broken.com.", minimisation);
+
+            assert_eq!(response.header.rcode, RCODE::NoErr, "{}", minimisation);
+            assert_eq!(addresses(&response), [Ipv4Addr::new(10, 0, 0, 4)]);
+        }
+    }
+
+    #[test]
+    fn minimisation_stops_below_a_missing_name() {
+        for minimisation in [QnameMinimisation::Strict, QnameMinimisation::Relaxed] {
+            let response = resolve_minimised("a.b.nope.example.com.", minimisation);
+
+            assert_eq!(response.header.rcode, RCODE::NameErr, "{}", minimisation);
+        }
+    }
+}
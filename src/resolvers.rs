@@ -0,0 +1,280 @@
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use rand::seq::SliceRandom;
+
+use crate::{
+    upstream::{Upstream, UpstreamError},
+    DNSMessage, RCODE,
+};
+
+/// Failures in a row before a resolver is treated as dead
+const MAX_FAILURES: u32 = 3;
+
+/// How long a dead resolver is left alone before we give it another go
+const HOLD_DOWN: Duration = Duration::from_secs(30);
+
+/// How a resolver is picked out of the list for each query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Always start with the first resolver, moving down the list on failure
+    Failover,
+    /// Take turns, starting each query with the next resolver along
+    RoundRobin,
+    /// Start with a resolver picked at random
+    Random,
+    /// Start with whichever resolver has been answering the quickest
+    Fastest,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "failover" => Ok(Strategy::Failover),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "random" => Ok(Strategy::Random),
+            "fastest" => Ok(Strategy::Fastest),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Strategy::Failover => "failover",
+            Strategy::RoundRobin => "round-robin",
+            Strategy::Random => "random",
+            Strategy::Fastest => "fastest",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// What we have learnt about one resolver from the queries sent to it
+#[derive(Debug, Default)]
+struct Health {
+    /// Smoothed round trip time, zero until the first answer so that a
+    /// resolver we know nothing about gets tried early on
+    srtt: Duration,
+    /// Failures since the last good answer
+    failures: u32,
+    last_failure: Option<Instant>,
+}
+
+impl Health {
+    fn is_dead(&self) -> bool {
+        self.failures >= MAX_FAILURES && self.last_failure.is_some_and(|t| t.elapsed() < HOLD_DOWN)
+    }
+
+    /// Fold a new measurement into the smoothed RTT, giving it the same
+    /// 1/8 weight as TCP does, RFC 6298
+    fn record_rtt(&mut self, rtt: Duration) {
+        self.srtt = if self.srtt.is_zero() {
+            rtt
+        } else {
+            (self.srtt * 7 + rtt) / 8
+        };
+    }
+}
+
+/// A list of resolvers to forward to and the strategy for picking between
+/// them
+///
+/// A query goes to one resolver at a time. If it fails to answer, or answers
+/// SERVFAIL or REFUSED, the next one in line is asked instead. Resolvers
+/// that keep failing are skipped for a while, unless every one of them is
+/// failing, in which case they are all tried anyway.
+#[derive(Debug)]
+pub struct ResolverSet {
+    addrs: Vec<String>,
+    health: Vec<Mutex<Health>>,
+    strategy: Strategy,
+    next: AtomicUsize,
+}
+
+impl ResolverSet {
+    pub fn new(addrs: Vec<String>, strategy: Strategy) -> ResolverSet {
+        ResolverSet {
+            health: addrs.iter().map(|_| Mutex::default()).collect(),
+            addrs,
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn addrs(&self) -> &[String] {
+        &self.addrs
+    }
+
+    fn health(&self, index: usize) -> std::sync::MutexGuard<'_, Health> {
+        self.health[index]
+            .lock()
+            .expect("resolver health lock poisoned")
+    }
+
+    /// The order to try the resolvers in for the next query
+    fn order(&self) -> Vec<usize> {
+        let mut order = (0..self.addrs.len()).collect::<Vec<_>>();
+
+        match self.strategy {
+            Strategy::Failover => {}
+            Strategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % order.len().max(1);
+                order.rotate_left(start);
+            }
+            Strategy::Random => order.shuffle(&mut rand::thread_rng()),
+            Strategy::Fastest => order.sort_by_key(|&i| self.health(i).srtt),
+        }
+
+        // The sort is stable, so live resolvers keep their order in front of
+        // the dead ones
+        order.sort_by_key(|&i| self.health(i).is_dead());
+
+        if !self.health(order[0]).is_dead() {
+            order.retain(|&i| !self.health(i).is_dead());
+        }
+
+        order
+    }
+
+    /// Forward `query` to each resolver in turn until one gives a usable
+    /// answer
+    ///
+    /// If none does, the last answer received is returned even though it is
+    /// an error, or the last failure if nobody answered at all.
+    pub fn exchange(
+        &self,
+        upstream: &Upstream,
+        query: &DNSMessage,
+    ) -> Result<DNSMessage, UpstreamError> {
+        let mut result = Err(UpstreamError::Timeout);
+
+        for index in self.order() {
+            let addr = &self.addrs[index];
+            println!("Asking resolver {}", addr);
+
+            let started = Instant::now();
+            let response = upstream.exchange(addr, query);
+            let rtt = started.elapsed();
+
+            let mut health = self.health(index);
+
+            match response {
+                Ok(response)
+                    if !matches!(response.header.rcode, RCODE::ServerFail | RCODE::Refused) =>
+                {
+                    health.record_rtt(rtt);
+                    health.failures = 0;
+
+                    return Ok(response);
+                }
+                Ok(response) => {
+                    eprintln!("Resolver {} answered {:?}", addr, response.header.rcode);
+                    result = Ok(response);
+                }
+                Err(e) => {
+                    eprintln!("Failed to query resolver {}: {}", addr, e);
+                    result = Err(e);
+                }
+            }
+
+            // A failure costs as much as the time we spent waiting on it, so
+            // a resolver that keeps timing out drifts to the back for
+            // `Fastest` even before it counts as dead
+            health.record_rtt(rtt);
+            health.failures += 1;
+            health.last_failure = Some(Instant::now());
+
+            if health.failures == MAX_FAILURES {
+                println!("Resolver {} marked as dead", addr);
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolvers(strategy: Strategy) -> ResolverSet {
+        let addrs = ["10.0.0.1:53", "10.0.0.2:53", "10.0.0.3:53"];
+        ResolverSet::new(addrs.map(str::to_string).to_vec(), strategy)
+    }
+
+    fn fail(set: &ResolverSet, index: usize, failures: u32) {
+        let mut health = set.health(index);
+        health.failures = failures;
+        health.last_failure = Some(Instant::now());
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let set = resolvers(Strategy::RoundRobin);
+
+        assert_eq!(set.order(), [0, 1, 2]);
+        assert_eq!(set.order(), [1, 2, 0]);
+        assert_eq!(set.order(), [2, 0, 1]);
+        assert_eq!(set.order(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn fastest_goes_first() {
+        let set = resolvers(Strategy::Fastest);
+        set.health(0).record_rtt(Duration::from_millis(80));
+        set.health(1).record_rtt(Duration::from_millis(20));
+        set.health(2).record_rtt(Duration::from_millis(50));
+
+        assert_eq!(set.order(), [1, 2, 0]);
+    }
+
+    #[test]
+    fn dead_resolvers_are_skipped() {
+        let set = resolvers(Strategy::Failover);
+
+        // a couple of failures aren't enough
+        fail(&set, 0, MAX_FAILURES - 1);
+        assert_eq!(set.order(), [0, 1, 2]);
+
+        fail(&set, 0, MAX_FAILURES);
+        assert_eq!(set.order(), [1, 2]);
+
+        // the rotation still applies to the ones left
+        let set = resolvers(Strategy::RoundRobin);
+        fail(&set, 1, MAX_FAILURES);
+        assert_eq!(set.order(), [0, 2]);
+        assert_eq!(set.order(), [2, 0]);
+    }
+
+    #[test]
+    fn dead_resolvers_get_another_go_after_the_hold_down() {
+        let set = resolvers(Strategy::Failover);
+        fail(&set, 0, MAX_FAILURES);
+        set.health(0).last_failure = Instant::now().checked_sub(HOLD_DOWN);
+
+        assert_eq!(set.order(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn all_are_tried_when_all_are_dead() {
+        let set = resolvers(Strategy::RoundRobin);
+        for index in 0..3 {
+            fail(&set, index, MAX_FAILURES);
+        }
+
+        assert_eq!(set.order(), [0, 1, 2]);
+        assert_eq!(set.order(), [1, 2, 0]);
+    }
+}
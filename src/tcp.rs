@@ -0,0 +1,270 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{pool::ThreadPool, DNSMessage, Server, Transport};
+
+/// How long a connection may sit with no queries before we close it,
+/// RFC 7766 section 6.2.3
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long an answer may take to write before we give up on a client that
+/// isn't reading, so it can't hold a worker forever
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a message may take to arrive once its first byte has, so a
+/// client sending a trickle at a time can't hold a connection open
+const FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most connections we read queries from at once, each one has a thread
+const MAX_CONNECTIONS: usize = 128;
+
+/// Largest message that fits behind the two byte length prefix
+pub const MAX_TCP_MESSAGE: usize = u16::MAX as usize;
+
+/// Accept DNS over TCP connections, giving each one its own thread to read
+/// queries on while the queries themselves are answered by `pool`
+///
+/// Connections past `MAX_CONNECTIONS` are closed straight away, the client
+/// can retry once the others are done, RFC 7766 section 6.2.2.
+pub fn serve(listener: TcpListener, server: Arc<Server>, pool: Arc<ThreadPool>) {
+    let open = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let Some(slot) = Slot::take(&open) else {
+                    eprintln!("Too many TCP connections, closing a new one");
+                    continue;
+                };
+
+                let server = Arc::clone(&server);
+                let pool = Arc::clone(&pool);
+                thread::spawn(move || {
+                    handle_connection(stream, server, pool);
+                    drop(slot);
+                });
+            }
+            Err(e) => eprintln!("Error accepting connection: {}", e),
+        }
+    }
+}
+
+/// One of the `MAX_CONNECTIONS` places for an open connection, given back
+/// when dropped
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn take(open: &Arc<AtomicUsize>) -> Option<Slot> {
+        open.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+            (n < MAX_CONNECTIONS).then_some(n + 1)
+        })
+        .ok()
+        .map(|_| Slot(Arc::clone(open)))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Serve every query sent down one connection
+///
+/// Queries may be pipelined, RFC 7766 section 6.2.1.1. Each one is answered
+/// separately and the answer is written as soon as it is ready, so
+/// answers can go back in a different order to the queries. The connection
+/// is closed once the client goes quiet with nothing left to answer.
+fn handle_connection(mut stream: TcpStream, server: Arc<Server>, pool: Arc<ThreadPool>) {
+    let peer = match stream.peer_addr() {
+        Ok(peer) => peer,
+        Err(e) => {
+            eprintln!("Error reading peer address: {}", e);
+            return;
+        }
+    };
+    println!("Accepted TCP connection from {}", peer);
+
+    let writer = match stream.try_clone() {
+        Ok(writer) => Arc::new(Mutex::new(writer)),
+        Err(e) => {
+            eprintln!("Error cloning stream for {}: {}", peer, e);
+            return;
+        }
+    };
+    let in_flight = Arc::new(AtomicUsize::new(0));
+
+    loop {
+        if let Err(e) = stream.set_read_timeout(Some(IDLE_TIMEOUT)) {
+            eprintln!("Error setting timeout for {}: {}", peer, e);
+            break;
+        }
+
+        // Going quiet between messages is fine while answers are still
+        // pending, nothing has been read yet that could be lost
+        let first = match wait_for_frame(&mut stream) {
+            Ok(Some(first)) => first,
+            Ok(None) => break,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                if in_flight.load(Ordering::SeqCst) > 0 {
+                    continue;
+                }
+
+                println!("Closing idle TCP connection from {}", peer);
+                break;
+            }
+            Err(e) => {
+                eprintln!("Error reading from {}: {}", peer, e);
+                break;
+            }
+        };
+
+        // Once a message has started there is no getting back in step with
+        // the framing if the rest of it doesn't arrive, so give up on the
+        // connection
+        let deadline = Instant::now() + FRAME_TIMEOUT;
+        let data = match read_frame_rest(&mut stream, first, deadline) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("Error reading from {}, closing: {}", peer, e);
+                break;
+            }
+        };
+
+        println!("Received {} bytes over TCP from {}", data.len(), peer);
+
+        let mut ndns = match DNSMessage::from_wire(&data) {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Failed to parse message from {}: {}", peer, e);
+                continue;
+            }
+        };
+        ndns.transport = Transport::TCP;
+
+        in_flight.fetch_add(1, Ordering::SeqCst);
+
+        let server = Arc::clone(&server);
+        let writer = Arc::clone(&writer);
+        let in_flight = Arc::clone(&in_flight);
+
+        pool.execute(move || {
+            let response = server.handle(ndns).to_wire_limited(MAX_TCP_MESSAGE);
+            let mut writer = writer.lock().expect("tcp writer lock poisoned");
+
+            // A partly written answer leaves the framing broken too, and a
+            // client that stopped reading isn't worth waiting on
+            if let Err(e) = write_frame(&mut writer, &response, WRITE_TIMEOUT) {
+                eprintln!("Error writing to {}, closing: {}", peer, e);
+                let _ = writer.shutdown(Shutdown::Both);
+            }
+            drop(writer);
+
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+
+    // Anything still being answered holds its own handle on the connection,
+    // so it stays open until the last answer is written
+}
+
+/// Wait for the first byte of the next message's length, `None` once the
+/// client has closed its side of the connection
+fn wait_for_frame(stream: &mut TcpStream) -> io::Result<Option<u8>> {
+    let mut first = [0; 1];
+
+    loop {
+        match stream.read(&mut first) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(first[0])),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Read the rest of a message whose length starts with `first`, giving up
+/// if it hasn't all arrived by `deadline`
+fn read_frame_rest(stream: &mut TcpStream, first: u8, deadline: Instant) -> io::Result<Vec<u8>> {
+    let mut second = [0; 1];
+    read_exact_by(stream, &mut second, deadline)?;
+
+    let mut data = vec![0; u16::from_be_bytes([first, second[0]]) as usize];
+    read_exact_by(stream, &mut data, deadline)?;
+
+    Ok(data)
+}
+
+/// Fill `buf` from the stream by `deadline`, the read counterpart of the
+/// loop in `write_frame`
+fn read_exact_by(stream: &mut TcpStream, buf: &mut [u8], deadline: Instant) -> io::Result<()> {
+    let mut filled = 0;
+
+    while filled < buf.len() {
+        let left = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|left| !left.is_zero())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "read timed out"))?;
+        stream.set_read_timeout(Some(left))?;
+
+        match stream.read(&mut buf[filled..]) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Read one length-prefixed message within `timeout`, `None` once the
+/// client has closed its side of the connection
+pub fn read_frame(stream: &mut TcpStream, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+    let deadline = Instant::now() + timeout;
+    stream.set_read_timeout(Some(timeout))?;
+
+    match wait_for_frame(stream)? {
+        Some(first) => read_frame_rest(stream, first, deadline).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Write one message behind its two byte length, RFC 1035 section 4.2.2,
+/// giving up if the whole of it can't be written within `timeout`
+///
+/// The socket's write timeout only bounds each write on its own, and a
+/// peer that reads a trickle at a time would keep resetting it.
+pub fn write_frame(stream: &mut TcpStream, data: &[u8], timeout: Duration) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(data.len() + 2);
+    frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    frame.extend_from_slice(data);
+
+    let deadline = Instant::now() + timeout;
+    let mut rest = frame.as_slice();
+
+    while !rest.is_empty() {
+        let left = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|left| !left.is_zero())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "write timed out"))?;
+        stream.set_write_timeout(Some(left))?;
+
+        match stream.write(rest) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => rest = &rest[n..],
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
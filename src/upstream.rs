@@ -0,0 +1,413 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    config::Config,
+    edns::EDNS_PAYLOAD_SIZE,
+    name::DomainName,
+    rdata::{RecordClass, RecordType},
+    tcp::{read_frame, write_frame},
+    DNSMessage, DNSQuery, WireError,
+};
+
+/// Longest we wait on any one attempt, however many retries came before
+const MAX_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of sockets queries are spread over, each on its own port
+const SOCKETS: usize = 16;
+
+/// Queries sent from one socket before it is swapped for a new one, and so
+/// a new port
+const QUERIES_PER_SOCKET: u32 = 100;
+
+/// How often a socket's reader checks whether the socket has been retired
+const RETIRE_CHECK: Duration = Duration::from_secs(1);
+
+/// Ways a query to the upstream resolver can fail
+#[derive(Debug, thiserror::Error)]
+pub enum UpstreamError {
+    #[error("network error: {0}")]
+    Io(#[from] io::Error),
+    #[error("bad response: {0}")]
+    Wire(#[from] WireError),
+    #[error("resolver closed the connection without answering")]
+    Closed,
+    #[error("no answer from resolver")]
+    Timeout,
+    #[error("resolver answered a different query")]
+    Mismatch,
+}
+
+/// What a reply has to carry to be matched up with the query it answers:
+/// the transaction ID and the question
+type PendingKey = (u16, Option<(DomainName, RecordType, RecordClass)>);
+
+fn pending_key(message: &DNSMessage) -> PendingKey {
+    let question = message
+        .queries
+        .first()
+        .map(|q| (q.qname.clone(), q.qtype, q.qclass));
+
+    (message.header.id, question)
+}
+
+/// A query we sent and are waiting on a reply to
+#[derive(Debug)]
+struct Waiting {
+    /// Where the reply has to come from
+    source: SocketAddr,
+    /// Our port the query went out on, which the reply has to come back to
+    port: u16,
+    id: u16,
+    /// The questions exactly as they were sent, case included
+    questions: Vec<DNSQuery>,
+    /// Whether the reply has to echo the case of the question back
+    exact_case: bool,
+    sender: Sender<DNSMessage>,
+}
+
+impl Waiting {
+    /// Check a reply really is the answer to this query, as far as we can
+    /// tell without DNSSEC
+    fn accepts(&self, source: SocketAddr, port: u16, response: &DNSMessage) -> bool {
+        if source != self.source
+            || port != self.port
+            || !answers(self.id, &self.questions, response)
+        {
+            return false;
+        }
+
+        !self.exact_case
+            || self
+                .questions
+                .iter()
+                .zip(&response.queries)
+                .all(|(sent, echoed)| sent.qname.eq_exact(&echoed.qname))
+    }
+}
+
+/// True if `response` is a reply to a query with this ID and these questions
+fn answers(id: u16, questions: &[DNSQuery], response: &DNSMessage) -> bool {
+    response.header.qr
+        && response.header.id == id
+        && response.queries.len() == questions.len()
+        && questions.iter().zip(&response.queries).all(|(sent, echoed)| {
+            sent.qname == echoed.qname && sent.qtype == echoed.qtype && sent.qclass == echoed.qclass
+        })
+}
+
+/// Queries waiting on a reply
+type Pending = Arc<Mutex<HashMap<PendingKey, Waiting>>>;
+
+/// A small set of UDP sockets shared by every query we send to resolvers
+///
+/// Any number of queries can be outstanding at once. Each socket has a
+/// background thread that reads every reply off it and passes it to the
+/// query with the same transaction ID and question, so a slow answer for one
+/// client never holds up anyone else.
+///
+/// Every query gets a random ID and goes out on a random one of the sockets,
+/// and a reply is only accepted if it comes from the address the query went
+/// to, arrives on the port it left from and echoes the ID and question back.
+/// Sockets are swapped for new ones after `QUERIES_PER_SOCKET` queries, so
+/// the source port keeps changing too, RFC 5452 section 9.2. With `use_0x20`
+/// the case of the question is randomised as well and has to come back
+/// unchanged, which gives an off-path attacker a lot more to guess.
+/// Anything that doesn't match is dropped.
+///
+/// Lost packets are dealt with by sending the query again, up to `retries`
+/// times, doubling the time we wait for an answer after each attempt.
+#[derive(Debug)]
+pub struct Upstream {
+    sockets: Vec<Mutex<Socket>>,
+    pending: Pending,
+    use_0x20: bool,
+    timeout: Duration,
+    retries: u32,
+}
+
+impl Upstream {
+    pub fn new(config: &Config) -> io::Result<Upstream> {
+        let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+        let sockets = (0..SOCKETS)
+            .map(|_| Socket::bind(&pending).map(Mutex::new))
+            .collect::<io::Result<_>>()?;
+
+        Ok(Upstream {
+            sockets,
+            pending,
+            use_0x20: config.use_0x20,
+            timeout: config.timeout,
+            retries: config.retries,
+        })
+    }
+
+    /// Ask the resolver at `addr` and wait for its answer
+    ///
+    /// The query goes out over UDP first. If the answer comes back with TC
+    /// set it is missing records, so the same query is asked again over TCP
+    /// and that answer is returned instead.
+    pub fn exchange(&self, addr: &str, query: &DNSMessage) -> Result<DNSMessage, UpstreamError> {
+        let response = self.exchange_udp(addr, query)?;
+
+        if !response.header.tc {
+            return Ok(response);
+        }
+
+        println!("Answer from {} was truncated, retrying over TCP", addr);
+
+        let mut query = query.clone();
+        query.header.id = rand::random();
+        exchange_tcp(addr, &query, self.timeout)
+    }
+
+    /// Pick a socket to send a query from, replacing it first if it has
+    /// been used for long enough
+    fn socket(&self) -> Arc<UdpSocket> {
+        let slot = &self.sockets[rand::random::<usize>() % self.sockets.len()];
+        let mut slot = slot.lock().expect("socket lock poisoned");
+
+        if slot.sent >= QUERIES_PER_SOCKET {
+            // Carrying on with the old port beats failing the query
+            match Socket::bind(&self.pending) {
+                Ok(fresh) => *slot = fresh,
+                Err(e) => eprintln!("Failed to bind a new upstream socket: {}", e),
+            }
+        }
+
+        slot.sent += 1;
+        Arc::clone(&slot.socket)
+    }
+
+    /// Send a query in a single datagram and wait for the matching reply
+    pub fn exchange_udp(
+        &self,
+        addr: &str,
+        query: &DNSMessage,
+    ) -> Result<DNSMessage, UpstreamError> {
+        let source = resolve(addr)?;
+        let socket = self.socket();
+        let port = socket.local_addr()?.port();
+        let (sender, receiver) = mpsc::channel();
+
+        let mut sent = query.clone();
+        if self.use_0x20 {
+            for q in sent.queries.iter_mut() {
+                q.qname = q.qname.with_random_case();
+            }
+        }
+
+        let key = {
+            let mut pending = self.pending.lock().expect("pending lock poisoned");
+
+            // Never reuse the client's ID, and pick again if the one we drew
+            // is already in use for the same question
+            let key = loop {
+                sent.header.id = rand::random();
+
+                let key = pending_key(&sent);
+                if !pending.contains_key(&key) {
+                    break key;
+                }
+            };
+
+            pending.insert(
+                key.clone(),
+                Waiting {
+                    source,
+                    port,
+                    id: sent.header.id,
+                    questions: sent.queries.clone(),
+                    exact_case: self.use_0x20,
+                    sender,
+                },
+            );
+            key
+        };
+
+        let wire = sent.to_wire();
+        let mut result = Err(UpstreamError::Timeout);
+        let mut timeout = self.timeout;
+
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                println!("No answer from {}, retry {} of {}", addr, attempt, self.retries);
+            }
+
+            if let Err(e) = socket.send_to(&wire, source) {
+                result = Err(e.into());
+                break;
+            }
+
+            // A reply to any of the attempts will do, they all carry the
+            // same ID and question
+            if let Ok(response) = receiver.recv_timeout(timeout) {
+                result = Ok(response);
+                break;
+            }
+
+            timeout = (timeout * 2).min(MAX_TIMEOUT);
+        }
+
+        self.pending
+            .lock()
+            .expect("pending lock poisoned")
+            .remove(&key);
+
+        let mut response = result?;
+        restore_case(&mut response, query);
+
+        Ok(response)
+    }
+}
+
+/// Put the client's spelling of the question back after 0x20, both in the
+/// question and on any record owned by the name asked about
+fn restore_case(response: &mut DNSMessage, query: &DNSMessage) {
+    for original in &query.queries {
+        for q in response.queries.iter_mut() {
+            if q.qname == original.qname {
+                q.qname = original.qname.clone();
+            }
+        }
+
+        let records = response
+            .ans
+            .iter_mut()
+            .chain(response.nsr.iter_mut())
+            .chain(response.arc.iter_mut());
+        for rr in records {
+            if rr.name == original.qname {
+                rr.name = original.qname.clone();
+            }
+        }
+    }
+}
+
+/// One of the upstream sockets and how many queries have gone out on it
+#[derive(Debug)]
+struct Socket {
+    socket: Arc<UdpSocket>,
+    sent: u32,
+}
+
+impl Socket {
+    /// Bind a socket to a port of the system's choosing, random on the
+    /// systems we run on, and start reading replies off it
+    fn bind(pending: &Pending) -> io::Result<Socket> {
+        let socket = Arc::new(UdpSocket::bind("0.0.0.0:0")?);
+        socket.set_read_timeout(Some(RETIRE_CHECK))?;
+
+        let port = socket.local_addr()?.port();
+        let dispatch_socket = Arc::clone(&socket);
+        let dispatch_pending = Arc::clone(pending);
+        thread::spawn(move || dispatch(dispatch_socket, port, dispatch_pending));
+
+        Ok(Socket { socket, sent: 0 })
+    }
+}
+
+/// Read replies off one socket, handing each one to the query waiting for
+/// it, until the socket has been replaced and nothing is sending on it
+fn dispatch(socket: Arc<UdpSocket>, port: u16, pending: Pending) {
+    let mut res_buffer = [0; EDNS_PAYLOAD_SIZE as usize];
+
+    loop {
+        let (res_size, source) = match socket.recv_from(&mut res_buffer) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                // Once it has been replaced and every query sent on it has
+                // finished, nobody is left to hand replies to
+                if Arc::strong_count(&socket) == 1 {
+                    return;
+                }
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Error receiving from resolver: {}", e);
+                continue;
+            }
+        };
+
+        let response = match DNSMessage::from_wire(&res_buffer[..res_size]) {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Failed to parse message from resolver {}: {}", source, e);
+                continue;
+            }
+        };
+
+        let mut pending = pending.lock().expect("pending lock poisoned");
+        let key = pending_key(&response);
+
+        // A reply that fails the checks is left alone rather than failing
+        // the query, the real answer may still be on its way
+        match pending.get(&key) {
+            Some(waiting) if waiting.accepts(source, port, &response) => {
+                let waiting = pending.remove(&key).expect("entry was just found");
+
+                // The query may have given up already, in which case there
+                // is nobody left to tell
+                let _ = waiting.sender.send(response);
+            }
+            _ => println!("Discarding unexpected reply from {}", source),
+        }
+    }
+}
+
+/// Look up the socket address of a resolver
+fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for resolver"))
+}
+
+/// Send a query over a fresh TCP connection and read back its answer
+pub fn exchange_tcp(
+    addr: &str,
+    query: &DNSMessage,
+    timeout: Duration,
+) -> Result<DNSMessage, UpstreamError> {
+    let addr = resolve(addr)?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+
+    write_frame(&mut stream, &query.to_wire(), timeout)?;
+
+    let data = read_frame(&mut stream, timeout)?.ok_or(UpstreamError::Closed)?;
+    let response = DNSMessage::from_wire(&data)?;
+
+    if !answers(query.header.id, &query.queries, &response) {
+        return Err(UpstreamError::Mismatch);
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn source_ports_change() {
+        let upstream = Upstream::new(&Config::default()).unwrap();
+
+        let ports: HashSet<u16> = (0..SOCKETS as u32 * QUERIES_PER_SOCKET * 2)
+            .map(|_| upstream.socket().local_addr().unwrap().port())
+            .collect();
+
+        // more queries than the sockets take between them, so some have
+        // been swapped for new ports
+        assert!(ports.len() > SOCKETS);
+    }
+}
@@ -0,0 +1,484 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    name::DomainName,
+    rdata::{parse_ttl, RData, RecordClass, RecordType},
+    DNSResource,
+};
+
+/// How deep `$INCLUDE` may nest before we assume a file includes itself
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Reasons a zone file can't be loaded
+#[derive(Debug, thiserror::Error)]
+pub enum ZoneFileError {
+    #[error("{path}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("{path}:{line}: {message}")]
+    Syntax {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+}
+
+/// Read the records out of a master file in the format of RFC 1035 section
+/// 5, with names relative to `origin` until a `$ORIGIN` says otherwise
+pub fn load(path: &Path, origin: &DomainName) -> Result<Vec<DNSResource>, ZoneFileError> {
+    let mut loader = Loader::default();
+    loader.read(path, origin.clone(), 0)?;
+
+    Ok(loader.records)
+}
+
+/// State that carries on across `$INCLUDE`d files
+#[derive(Default)]
+struct Loader {
+    records: Vec<DNSResource>,
+    /// From `$TTL`, RFC 2308 section 4
+    default_ttl: Option<u32>,
+    /// The TTL of the last record that gave one, used when there's no `$TTL`
+    last_ttl: Option<u32>,
+}
+
+impl Loader {
+    fn read(
+        &mut self,
+        path: &Path,
+        mut origin: DomainName,
+        depth: usize,
+    ) -> Result<(), ZoneFileError> {
+        let text = fs::read_to_string(path).map_err(|source| ZoneFileError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+        let entries = tokenize(&text).map_err(|(line, message)| ZoneFileError::Syntax {
+            path: path.to_path_buf(),
+            line,
+            message,
+        })?;
+
+        // The owner of the last record, for lines that start with a blank
+        let mut owner: Option<DomainName> = None;
+
+        for entry in entries {
+            let err = |message: String| ZoneFileError::Syntax {
+                path: path.to_path_buf(),
+                line: entry.line,
+                message,
+            };
+            let name = |text: &str, origin: &DomainName| {
+                DomainName::from_relative(text, origin)
+                    .map_err(|e| err(format!("bad name {:?}: {}", text, e)))
+            };
+
+            let mut tokens = entry.tokens.iter().map(|t| t.text.as_str());
+
+            let first = &entry.tokens[0];
+            if !entry.blank_owner && !first.quoted && first.text.starts_with('$') {
+                tokens.next();
+                let args = tokens.collect::<Vec<_>>();
+
+                match (first.text.to_ascii_uppercase().as_str(), args.as_slice()) {
+                    ("$ORIGIN", [new]) => origin = name(new, &origin)?,
+                    ("$TTL", [ttl]) => {
+                        let ttl =
+                            parse_ttl(ttl).ok_or_else(|| err(format!("bad TTL {:?}", ttl)))?;
+                        self.default_ttl = Some(ttl);
+                    }
+                    ("$INCLUDE", [file, rest @ ..]) if rest.len() <= 1 => {
+                        if depth >= MAX_INCLUDE_DEPTH {
+                            return Err(err("$INCLUDE nested too deep".to_string()));
+                        }
+
+                        // The included file can have its own origin, but
+                        // whatever it does with it doesn't leak back here
+                        let included_origin = match rest {
+                            [new] => name(new, &origin)?,
+                            _ => origin.clone(),
+                        };
+                        let file = path.parent().unwrap_or(Path::new(".")).join(file);
+
+                        self.read(&file, included_origin, depth + 1)?;
+                    }
+                    (directive, _) => return Err(err(format!("bad {} line", directive))),
+                }
+
+                continue;
+            }
+
+            let owner_name = match entry.blank_owner {
+                true => owner
+                    .clone()
+                    .ok_or_else(|| err("no previous owner to carry on from".to_string()))?,
+                false => name(tokens.next().unwrap_or_default(), &origin)?,
+            };
+            owner = Some(owner_name.clone());
+
+            // The TTL and class are both optional and may come in either order
+            let mut ttl = None;
+            let mut class = None;
+            let rtype = loop {
+                let field = tokens
+                    .next()
+                    .ok_or_else(|| err("missing record type".to_string()))?;
+
+                if ttl.is_none() && field.starts_with(|c: char| c.is_ascii_digit()) {
+                    ttl =
+                        Some(parse_ttl(field).ok_or_else(|| err(format!("bad TTL {:?}", field)))?);
+                    continue;
+                }
+                if class.is_none() {
+                    if let Ok(c) = field.parse::<RecordClass>() {
+                        class = Some(c);
+                        continue;
+                    }
+                }
+
+                break field
+                    .parse::<RecordType>()
+                    .map_err(|e| err(e.to_string()))?;
+            };
+
+            if ttl.is_some() {
+                self.last_ttl = ttl;
+            }
+            let ttl = ttl
+                .or(self.default_ttl)
+                .or(self.last_ttl)
+                .ok_or_else(|| err("no TTL given and no $TTL to fall back on".to_string()))?;
+
+            let fields = tokens.map(str::to_string).collect::<Vec<_>>();
+            let rdata = RData::from_presentation(rtype, &fields, &origin)
+                .map_err(|e| err(e.to_string()))?;
+
+            self.records.push(DNSResource {
+                name: owner_name,
+                class: class.unwrap_or(RecordClass::IN),
+                ttl,
+                rdata,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// A word on a line, with any quotes taken off but escapes left in
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+/// The words of one record or directive, which may span several lines
+/// inside parentheses
+struct Entry {
+    /// Where the entry starts, for error messages
+    line: usize,
+    /// The first line started with a space or tab, so the owner is left out
+    blank_owner: bool,
+    tokens: Vec<Token>,
+}
+
+/// Split a master file into entries, dropping comments and blank lines
+///
+/// Errors come back as the line number and a description.
+fn tokenize(text: &str) -> Result<Vec<Entry>, (usize, String)> {
+    let mut lexer = Lexer {
+        entries: vec![],
+        entry: Entry {
+            line: 1,
+            blank_owner: false,
+            tokens: vec![],
+        },
+        word: None,
+    };
+
+    let mut line = 1;
+    let mut line_start = true;
+    let mut parens = 0;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if line_start {
+            lexer.entry.line = line;
+            lexer.entry.blank_owner = c == ' ' || c == '\t';
+            line_start = false;
+        }
+
+        match c {
+            '\\' => {
+                let next = chars
+                    .next()
+                    .ok_or((line, "escape at end of file".to_string()))?;
+                if next == '\n' {
+                    line += 1;
+                }
+
+                let word = lexer.word.get_or_insert_with(String::new);
+                word.push(c);
+                word.push(next);
+            }
+            '"' => {
+                lexer.end_word();
+
+                let mut quoted = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            quoted.push('\\');
+                            quoted.extend(chars.next());
+                        }
+                        Some('\n') | None => return Err((line, "unterminated string".to_string())),
+                        Some(c) => quoted.push(c),
+                    }
+                }
+
+                lexer.entry.tokens.push(Token {
+                    text: quoted,
+                    quoted: true,
+                });
+            }
+            ';' => {
+                lexer.end_word();
+                while chars.next_if(|&c| c != '\n').is_some() {}
+            }
+            '(' => {
+                lexer.end_word();
+                parens += 1;
+            }
+            ')' => {
+                lexer.end_word();
+                if parens == 0 {
+                    return Err((line, "unbalanced ')'".to_string()));
+                }
+                parens -= 1;
+            }
+            '\n' => {
+                lexer.end_word();
+                if parens == 0 {
+                    lexer.end_entry();
+                    line_start = true;
+                }
+                line += 1;
+            }
+            c if c.is_whitespace() => lexer.end_word(),
+            c => lexer.word.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if parens > 0 {
+        return Err((lexer.entry.line, "unclosed '('".to_string()));
+    }
+    lexer.end_word();
+    lexer.end_entry();
+
+    Ok(lexer.entries)
+}
+
+struct Lexer {
+    entries: Vec<Entry>,
+    entry: Entry,
+    word: Option<String>,
+}
+
+impl Lexer {
+    fn end_word(&mut self) {
+        if let Some(text) = self.word.take() {
+            self.entry.tokens.push(Token {
+                text,
+                quoted: false,
+            });
+        }
+    }
+
+    fn end_entry(&mut self) {
+        let tokens = std::mem::take(&mut self.entry.tokens);
+
+        if !tokens.is_empty() {
+            self.entries.push(Entry {
+                line: self.entry.line,
+                blank_owner: self.entry.blank_owner,
+                tokens,
+            });
+        }
+    }
+}
+
+/// Zone files written out for a test, deleted again when dropped
+#[cfg(test)]
+pub struct TempZones {
+    dir: PathBuf,
+    first: PathBuf,
+}
+
+#[cfg(test)]
+impl TempZones {
+    /// Write `files` into a directory of their own, named after `test` so
+    /// tests running at the same time don't trip over each other
+    pub fn write(test: &str, files: &[(&str, &str)]) -> TempZones {
+        let dir = std::env::temp_dir().join(format!("zones-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).expect("temp dir");
+
+        for (name, text) in files {
+            fs::write(dir.join(name), text).expect("write zone");
+        }
+
+        TempZones {
+            first: dir.join(files[0].0),
+            dir,
+        }
+    }
+
+    /// The first of the files, the one to load
+    pub fn path(&self) -> &Path {
+        &self.first
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempZones {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(test: &str, files: &[(&str, &str)]) -> TempZones {
+        TempZones::write(&format!("zonefile-{}", test), files)
+    }
+
+    fn name(s: &str) -> DomainName {
+        s.parse().expect("name")
+    }
+
+    #[test]
+    fn soa_spread_over_lines_in_parentheses() {
+        let zone = write(
+            "soa",
+            &[(
+                "example.zone",
+                "$ORIGIN example.com.\n\
+                 $TTL 1h\n\
+                 @ IN SOA ns1 hostmaster (\n\
+                 \x20       2024010101 ; serial\n\
+                 \x20       2h         ; refresh\n\
+                 \x20       15m 1w\n\
+                 \x20       300 )      ; minimum\n\
+                 \x20   NS ns1\n",
+            )],
+        );
+
+        let records = load(zone.path(), &DomainName::root()).unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].name, name("example.com."));
+        assert_eq!(records[0].ttl, 3600);
+        assert_eq!(
+            records[0].rdata,
+            RData::SOA {
+                mname: name("ns1.example.com."),
+                rname: name("hostmaster.example.com."),
+                serial: 2024010101,
+                refresh: 7200,
+                retry: 900,
+                expire: 604800,
+                minimum: 300,
+            }
+        );
+
+        // The line starting with a blank carries on with the same owner
+        assert_eq!(records[1].name, name("example.com."));
+        assert_eq!(records[1].rdata, RData::NS(name("ns1.example.com.")));
+    }
+
+    #[test]
+    fn include_with_its_own_origin() {
+        let zone = write(
+            "include",
+            &[
+                (
+                    "example.zone",
+                    "$TTL 60\n\
+                     @ SOA ns1 hostmaster 1 2 3 4 5\n\
+                     $INCLUDE sub.zone sub\n\
+                     after A 192.0.2.2\n",
+                ),
+                (
+                    "sub.zone",
+                    "@ A 192.0.2.1\n\
+                     $ORIGIN deeper\n\
+                     x 120 A 192.0.2.3\n",
+                ),
+            ],
+        );
+
+        let records = load(zone.path(), &name("example.com.")).unwrap();
+        let owners = records.iter().map(|rr| rr.name.clone()).collect::<Vec<_>>();
+
+        assert_eq!(
+            owners,
+            [
+                name("example.com."),
+                name("sub.example.com."),
+                name("x.deeper.sub.example.com."),
+                // The included file's $ORIGIN stays in that file
+                name("after.example.com."),
+            ]
+        );
+        assert_eq!(records[2].ttl, 120);
+        assert_eq!(records[3].ttl, 60);
+    }
+
+    #[test]
+    fn quoted_strings_and_escapes() {
+        let zone = write(
+            "txt",
+            &[(
+                "example.zone",
+                "$TTL 60\n\
+                 txt TXT \"a \\\"quoted\\\" ; not a comment\" plain\\ word \\065\n",
+            )],
+        );
+
+        let records = load(zone.path(), &name("example.com.")).unwrap();
+
+        assert_eq!(
+            records[0].rdata,
+            RData::TXT(vec![
+                b"a \"quoted\" ; not a comment".to_vec(),
+                b"plain word".to_vec(),
+                b"A".to_vec(),
+            ])
+        );
+    }
+
+    #[test]
+    fn errors_give_the_line() {
+        let unclosed = write(
+            "unclosed",
+            &[("example.zone", "$TTL 60\n@ SOA a b (1 2 3\n")],
+        );
+        let no_ttl = write("no-ttl", &[("example.zone", "www A 192.0.2.1\n")]);
+
+        assert!(matches!(
+            load(unclosed.path(), &DomainName::root()),
+            Err(ZoneFileError::Syntax { line: 2, .. })
+        ));
+        assert!(matches!(
+            load(no_ttl.path(), &DomainName::root()),
+            Err(ZoneFileError::Syntax { line: 1, .. })
+        ));
+    }
+}